@@ -0,0 +1,245 @@
+//! Sticky proxies per target
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use http::uri::Scheme;
+use hyper::Uri;
+
+use crate::health::Health;
+use crate::Proxy;
+
+/// Keeps the connections to a target going through the same proxy for a while
+///
+/// The first connection to a target pins the proxy it went through, and the following
+/// connections to the target try this proxy first, ahead of the order of the
+/// [`ProxyPool`](crate::ProxyPool), until the pin expires. The pin is evicted early when the
+/// proxy becomes unhealthy (see [`HealthCheck`](crate::HealthCheck)) or no longer matches the
+/// target, and when [`Failover`](crate::Failover) used another proxy, that proxy is pinned
+/// instead.
+///
+/// Targets are keyed by their authority (`host:port`) unless a key function is set. The pins are
+/// shared by the clones of the affinity.
+///
+/// # Example
+/// ```rust
+/// use std::time::Duration;
+/// use hyper_proxy::Affinity;
+///
+/// let mut affinity = Affinity::new(Duration::from_secs(600));
+/// // one proxy per host, whatever the port
+/// affinity.set_key(|uri| uri.host().unwrap_or_default().to_owned());
+/// ```
+#[derive(Clone)]
+pub struct Affinity {
+    ttl: Duration,
+    key: Arc<dyn Fn(&Uri) -> String + Send + Sync>,
+    pins: Arc<Mutex<HashMap<String, Pin>>>,
+}
+
+struct Pin {
+    proxy: Uri,
+    expires: Instant,
+}
+
+impl fmt::Debug for Affinity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Affinity")
+            .field("ttl", &self.ttl)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Affinity {
+    /// Create an affinity pinning the proxy of a target for `ttl`
+    pub fn new(ttl: Duration) -> Affinity {
+        Affinity {
+            ttl,
+            key: Arc::new(authority),
+            pins: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Set the function keying the targets, the targets with the same key sharing their proxy
+    pub fn set_key<F>(&mut self, key: F)
+    where
+        F: Fn(&Uri) -> String + Send + Sync + 'static,
+    {
+        self.key = Arc::new(key);
+    }
+
+    /// Get the uri of the proxy pinned for the target, if any
+    pub fn pinned(&self, uri: &Uri) -> Option<Uri> {
+        let pins = self.pins.lock().unwrap();
+        let pin = pins.get(&(self.key)(uri))?;
+        Some(pin.proxy.clone()).filter(|_| pin.expires > Instant::now())
+    }
+
+    /// Moves the proxy pinned for `uri` first, evicting its pin if expired, unhealthy or no
+    /// longer a candidate
+    pub(crate) fn prefer(
+        &self,
+        uri: &Uri,
+        candidates: &mut [Option<Proxy>],
+        health: Option<&Health>,
+    ) {
+        let key = (self.key)(uri);
+        let mut pins = self.pins.lock().unwrap();
+        let pin = match pins.get(&key) {
+            Some(pin) => pin,
+            None => return,
+        };
+        let position = candidates
+            .iter()
+            .position(|p| p.as_ref().is_some_and(|p| p.uri == pin.proxy));
+        let healthy = |proxy: &Option<Proxy>| match (health, proxy) {
+            (Some(health), Some(proxy)) => health.is_healthy(proxy),
+            _ => true,
+        };
+        match position {
+            Some(i) if pin.expires > Instant::now() && healthy(&candidates[i]) => {
+                candidates[..=i].rotate_right(1)
+            }
+            _ => {
+                pins.remove(&key);
+            }
+        }
+    }
+
+    /// Pins `proxy` for `uri` after a successful connection, unless it is already pinned
+    pub(crate) fn record(&self, uri: &Uri, proxy: &Proxy) {
+        let key = (self.key)(uri);
+        let now = Instant::now();
+        let mut pins = self.pins.lock().unwrap();
+        if pins
+            .get(&key)
+            .is_some_and(|p| p.proxy == proxy.uri && p.expires > now)
+        {
+            return;
+        }
+        pins.retain(|_, p| p.expires > now);
+        pins.insert(
+            key,
+            Pin {
+                proxy: proxy.uri.clone(),
+                expires: now + self.ttl,
+            },
+        );
+    }
+}
+
+/// The default key of a target, its `host:port`
+fn authority(uri: &Uri) -> String {
+    let port = uri
+        .port_u16()
+        .unwrap_or(if uri.scheme() == Some(&Scheme::HTTP) {
+            80
+        } else {
+            443
+        });
+    format!("{}:{}", uri.host().unwrap_or_default(), port)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::Duration;
+
+    use hyper_util::client::legacy::connect::HttpConnector;
+    use tokio::runtime::Runtime;
+    use tower_service::Service;
+
+    use super::Affinity;
+    use crate::{BoxConnector, HealthCheck, Intercept, Proxy, ProxyConnector, ProxyPool, Strategy};
+
+    /// Runs a proxy accepting the CONNECT requests of `connections` connections, and then closing
+    /// its listener
+    fn mock_proxy(connections: usize) -> Proxy {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for _ in 0..connections {
+                let (mut sock, _) = listener.accept().unwrap();
+                let mut buf = [0u8; 4096];
+                let _ = sock.read(&mut buf).unwrap();
+                sock.write_all(b"HTTP/1.1 200 OK\r\n\r\n").unwrap();
+            }
+        });
+        let mut proxy = Proxy::new(Intercept::All, format!("http://{}", addr).parse().unwrap());
+        proxy.force_connect();
+        proxy
+    }
+
+    fn connector(proxies: &[Proxy], ttl: Duration) -> ProxyConnector<BoxConnector<HttpConnector>> {
+        let mut connector = ProxyConnector::unsecured(BoxConnector(HttpConnector::new()));
+        connector.extend_proxies(proxies.iter().cloned());
+        connector.set_pool(Some(ProxyPool::new(Strategy::RoundRobin)));
+        connector.set_affinity(Some(Affinity::new(ttl)));
+        connector
+    }
+
+    #[test]
+    fn test_affinity_sticks() {
+        let proxies = [mock_proxy(4), mock_proxy(4)];
+        let mut connector = connector(&proxies, Duration::from_secs(60));
+
+        Runtime::new().unwrap().block_on(async {
+            let uri: http::Uri = "http://example.org/".parse().unwrap();
+            let first = connector.call(uri.clone()).await.unwrap();
+            for _ in 0..2 {
+                let stream = connector.call(uri.clone()).await.unwrap();
+                assert_eq!(stream.proxy(), first.proxy());
+            }
+            let affinity = connector.affinity().unwrap();
+            assert_eq!(affinity.pinned(&uri).as_ref(), first.proxy());
+
+            // another target follows the pool
+            let other = "http://example.com/".parse().unwrap();
+            let stream = connector.call(other).await.unwrap();
+            assert_ne!(stream.proxy(), first.proxy());
+        });
+    }
+
+    #[test]
+    fn test_affinity_expires() {
+        let proxies = [mock_proxy(1), mock_proxy(1)];
+        let mut connector = connector(&proxies, Duration::ZERO);
+
+        Runtime::new().unwrap().block_on(async {
+            let uri: http::Uri = "http://example.org/".parse().unwrap();
+            let first = connector.call(uri.clone()).await.unwrap();
+            let second = connector.call(uri.clone()).await.unwrap();
+            assert_ne!(first.proxy(), second.proxy());
+            assert!(connector.affinity().unwrap().pinned(&uri).is_none());
+        });
+    }
+
+    #[test]
+    fn test_affinity_evicts_unhealthy() {
+        let proxies = [mock_proxy(1), mock_proxy(1)];
+        let mut connector = connector(&proxies, Duration::from_secs(60));
+        let mut health_check = HealthCheck::new("http://canary.example".parse().unwrap());
+        health_check.set_failure_threshold(1);
+        health_check.set_probe_interval(Duration::from_secs(3600));
+        connector.set_health_check(Some(health_check));
+
+        Runtime::new().unwrap().block_on(async {
+            let uri: http::Uri = "http://example.org/".parse().unwrap();
+            let first = connector.call(uri.clone()).await.unwrap();
+            let pinned = first.proxy().cloned();
+            // the pinned proxy is down, and its circuit opens
+            connector.call(uri.clone()).await.unwrap_err();
+
+            let stream = connector.call(uri.clone()).await.unwrap();
+            assert_ne!(stream.proxy().cloned(), pinned);
+            assert_eq!(
+                connector.affinity().unwrap().pinned(&uri).as_ref(),
+                stream.proxy()
+            );
+        });
+    }
+}
@@ -0,0 +1,567 @@
+//! Proxy authentication challenges and credentials
+
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+
+use headers::authorization::{Authorization, Credentials};
+use http::header::{HeaderMap, HeaderValue, PROXY_AUTHENTICATE};
+use http::Uri;
+
+use crate::{digest, ntlm, Error};
+
+/// A challenge of a `Proxy-Authenticate` header
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Challenge {
+    scheme: String,
+    token68: Option<String>,
+    params: Vec<(String, String)>,
+}
+
+impl Challenge {
+    /// Get the authentication scheme, e.g. `Basic`
+    pub fn scheme(&self) -> &str {
+        &self.scheme
+    }
+
+    /// Get the token following the scheme, for schemes not using parameters (e.g. `NTLM`)
+    pub fn token68(&self) -> Option<&str> {
+        self.token68.as_deref()
+    }
+
+    /// Get a parameter by its (case insensitive) name
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Get all the parameters, in order
+    pub fn params(&self) -> &[(String, String)] {
+        &self.params
+    }
+
+    /// Get the realm parameter
+    pub fn realm(&self) -> Option<&str> {
+        self.param("realm")
+    }
+
+    /// Parses all the challenges of the `Proxy-Authenticate` headers
+    ///
+    /// Malformed headers are ignored.
+    pub fn parse_all(headers: &HeaderMap) -> Vec<Challenge> {
+        headers
+            .get_all(PROXY_AUTHENTICATE)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| Parser(value.as_bytes()).challenges().unwrap_or_default())
+            .collect()
+    }
+}
+
+/// A minimal parser of RFC 7235 challenge lists
+struct Parser<'a>(&'a [u8]);
+
+impl<'a> Parser<'a> {
+    fn challenges(mut self) -> Option<Vec<Challenge>> {
+        let mut challenges: Vec<Challenge> = Vec::new();
+        loop {
+            self.skip(|b| b == b' ' || b == b'\t' || b == b',');
+            if self.0.is_empty() {
+                return Some(challenges);
+            }
+
+            // either a new challenge, or a parameter of the current one
+            let token = self.token()?;
+            self.skip(|b| b == b' ' || b == b'\t');
+            match (self.0.first(), challenges.last_mut()) {
+                (Some(b'='), Some(challenge)) if challenge.token68.is_none() => {
+                    self.0 = &self.0[1..];
+                    self.skip(|b| b == b' ' || b == b'\t');
+                    let value = match self.0.first() {
+                        Some(b'"') => self.quoted()?,
+                        _ => self.token()?,
+                    };
+                    challenge.params.push((token, value));
+                }
+                (Some(b'='), _) => return None,
+                _ => challenges.push(Challenge {
+                    scheme: token,
+                    token68: self.token68(),
+                    params: Vec::new(),
+                }),
+            }
+        }
+    }
+
+    fn skip(&mut self, f: impl Fn(u8) -> bool) {
+        let n = self.0.iter().take_while(|b| f(**b)).count();
+        self.0 = &self.0[n..];
+    }
+
+    fn token(&mut self) -> Option<String> {
+        let n = self
+            .0
+            .iter()
+            .take_while(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(b))
+            .count();
+        if n == 0 {
+            return None;
+        }
+        let token = String::from_utf8_lossy(&self.0[..n]).into_owned();
+        self.0 = &self.0[n..];
+        Some(token)
+    }
+
+    /// Parses a token68 after a scheme, leaving auth params untouched
+    fn token68(&mut self) -> Option<String> {
+        let n = self
+            .0
+            .iter()
+            .take_while(|b| b.is_ascii_alphanumeric() || b"-._~+/".contains(b))
+            .count();
+        let padding = self.0[n..].iter().take_while(|b| **b == b'=').count();
+        let end = self.0[n + padding..]
+            .iter()
+            .find(|b| **b != b' ' && **b != b'\t');
+        // `name=value` is a parameter, not a token68
+        if n == 0 || end.is_some_and(|b| *b != b',') {
+            return None;
+        }
+        let token = String::from_utf8_lossy(&self.0[..n + padding]).into_owned();
+        self.0 = &self.0[n + padding..];
+        Some(token)
+    }
+
+    fn quoted(&mut self) -> Option<String> {
+        let mut value = Vec::new();
+        let mut i = 1;
+        loop {
+            match *self.0.get(i)? {
+                b'"' => break,
+                b'\\' => {
+                    value.push(*self.0.get(i + 1)?);
+                    i += 2;
+                }
+                b => {
+                    value.push(b);
+                    i += 1;
+                }
+            }
+        }
+        self.0 = &self.0[i + 1..];
+        Some(String::from_utf8_lossy(&value).into_owned())
+    }
+}
+
+/// A `407 Proxy Authentication Required` response, given to a credentials provider
+#[derive(Debug, Clone)]
+pub struct ProxyChallenge {
+    proxy_uri: Uri,
+    challenges: Vec<Challenge>,
+    attempt: usize,
+}
+
+impl ProxyChallenge {
+    /// Get the uri of the proxy requiring authentication
+    pub fn proxy_uri(&self) -> &Uri {
+        &self.proxy_uri
+    }
+
+    /// Get the challenges of the `Proxy-Authenticate` headers
+    pub fn challenges(&self) -> &[Challenge] {
+        &self.challenges
+    }
+
+    /// Get the realm of the first challenge having one
+    pub fn realm(&self) -> Option<&str> {
+        self.challenges.iter().find_map(Challenge::realm)
+    }
+
+    /// Get the number of the attempt, starting at 1 for the first 407 response
+    pub fn attempt(&self) -> usize {
+        self.attempt
+    }
+}
+
+/// Username and password returned by a credentials provider
+#[derive(Clone, PartialEq, Eq)]
+pub struct ProxyCredentials {
+    username: String,
+    password: String,
+}
+
+impl fmt::Debug for ProxyCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ProxyCredentials")
+            .field("username", &self.username)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ProxyCredentials {
+    /// Create new credentials
+    pub fn new<U: Into<String>, P: Into<String>>(username: U, password: P) -> ProxyCredentials {
+        ProxyCredentials {
+            username: username.into(),
+            password: password.into(),
+        }
+    }
+
+    /// Get the username
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+
+    /// Get the password
+    pub fn password(&self) -> &str {
+        &self.password
+    }
+}
+
+type ProviderFuture = Pin<Box<dyn Future<Output = Option<ProxyCredentials>> + Send>>;
+
+/// An async callback returning credentials for a proxy challenge
+#[derive(Clone)]
+pub(crate) struct CredentialsProvider(Arc<dyn Fn(ProxyChallenge) -> ProviderFuture + Send + Sync>);
+
+impl CredentialsProvider {
+    /// Whether both providers are the same callback
+    pub(crate) fn ptr_eq(&self, other: &CredentialsProvider) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl fmt::Debug for CredentialsProvider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("_")
+    }
+}
+
+impl CredentialsProvider {
+    pub(crate) fn new<F, Fut>(f: F) -> CredentialsProvider
+    where
+        F: Fn(ProxyChallenge) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Option<ProxyCredentials>> + Send + 'static,
+    {
+        CredentialsProvider(Arc::new(move |challenge| Box::pin(f(challenge))))
+    }
+}
+
+/// A connection-based authentication scheme, such as `Negotiate` (SPNEGO/Kerberos)
+///
+/// When a proxy answers a CONNECT request with a challenge of this scheme, a new
+/// [`ProxyAuthContext`] is started for the connection and fed the successive challenges of the
+/// proxy, each token it returns being sent back in a `Proxy-Authorization` header on the same
+/// connection, until the proxy accepts the tunnel.
+///
+/// # Example
+/// ```rust
+/// use hyper_proxy::{Challenge, Error, ProxyAuthContext, ProxyAuthenticator};
+/// use hyper::Uri;
+///
+/// struct Negotiate;
+///
+/// struct Context {
+///     round: usize,
+/// }
+///
+/// impl ProxyAuthenticator for Negotiate {
+///     fn scheme(&self) -> &str {
+///         "Negotiate"
+///     }
+///
+///     fn start(&self, _proxy_uri: &Uri, _target: &str) -> Result<Box<dyn ProxyAuthContext>, Error> {
+///         Ok(Box::new(Context { round: 0 }))
+///     }
+/// }
+///
+/// impl ProxyAuthContext for Context {
+///     fn next_token(&mut self, challenge: &Challenge) -> Result<Option<String>, Error> {
+///         // a real implementation would call into GSSAPI or SSPI with `challenge.token68()`
+///         self.round += 1;
+///         Ok(Some(format!("token{}", self.round)))
+///     }
+/// }
+/// ```
+pub trait ProxyAuthenticator: Send + Sync {
+    /// The authentication scheme, compared case-insensitively with the challenges of the proxy
+    fn scheme(&self) -> &str;
+
+    /// Starts the authentication of a new connection to `proxy_uri`, tunneling to `target`
+    /// (`host:port`)
+    fn start(&self, proxy_uri: &Uri, target: &str) -> Result<Box<dyn ProxyAuthContext>, Error>;
+}
+
+impl fmt::Debug for dyn ProxyAuthenticator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("ProxyAuthenticator")
+            .field(&self.scheme())
+            .finish()
+    }
+}
+
+/// The state of a [`ProxyAuthenticator`] exchange on one connection
+pub trait ProxyAuthContext: Send {
+    /// Returns the token answering the challenge, usually computed from its
+    /// [`Challenge::token68`], or `None` to give up with
+    /// [`Error::ProxyAuthenticationRequired`]
+    ///
+    /// The first challenge of a context has no token.
+    fn next_token(&mut self, challenge: &Challenge) -> Result<Option<String>, Error>;
+}
+
+/// Answers the 407 responses of a proxy, using its credentials or credentials provider
+#[derive(Debug, Clone)]
+pub(crate) struct Authenticator {
+    pub(crate) credentials: Option<ProxyCredentials>,
+    pub(crate) provider: Option<CredentialsProvider>,
+    pub(crate) max_retries: usize,
+    /// The connection-based schemes, by order of preference
+    pub(crate) authenticators: Vec<Arc<dyn ProxyAuthenticator>>,
+    /// The Digest session, shared by all the connections to the proxy
+    pub(crate) digest: Arc<Mutex<Option<digest::Session>>>,
+}
+
+impl Authenticator {
+    /// Returns the `Proxy-Authorization` header to send along a new request, if an earlier
+    /// Digest challenge can be answered again
+    pub(crate) fn preemptive(&self, method: &str, uri: &str) -> Option<HeaderValue> {
+        let mut session = self.digest.lock().unwrap();
+        session.as_mut()?.authorization(method, uri).ok()
+    }
+
+    /// Returns the `Proxy-Authorization` header answering the challenges of a 407 response to
+    /// a `method` request to `uri`
+    pub(crate) async fn respond(
+        &self,
+        exchange: &mut Exchange,
+        proxy_uri: &Uri,
+        headers: &HeaderMap,
+        method: &str,
+        uri: &str,
+    ) -> Result<HeaderValue, Error> {
+        let challenges = Challenge::parse_all(headers);
+
+        if exchange.connection_based {
+            if let Some(header) = self.respond_custom(exchange, proxy_uri, &challenges, uri)? {
+                return Ok(header);
+            }
+        }
+
+        // NTLM authenticates the connection, so it needs to be kept during the exchange
+        let ntlm = challenges
+            .iter()
+            .find(|c| c.scheme.eq_ignore_ascii_case("ntlm"))
+            .filter(|_| exchange.connection_based);
+        if let Some(ntlm) = ntlm {
+            let message = match (exchange.ntlm.take(), ntlm.token68()) {
+                (Some(negotiation), Some(challenge)) => negotiation.authenticate(challenge)?,
+                _ => {
+                    let credentials = self.credentials(exchange, proxy_uri, &challenges).await?;
+                    let (negotiation, message) = ntlm::Negotiation::start(credentials);
+                    exchange.ntlm = Some(negotiation);
+                    message
+                }
+            };
+            return header(format!("NTLM {}", message));
+        }
+
+        if let Some(challenge) = digest::select(&challenges).cloned() {
+            // a stale nonce is renewed without asking for credentials again
+            if digest::is_stale(&challenge) {
+                if let Some(session) = self.digest.lock().unwrap().as_mut() {
+                    self.next_round(exchange)?;
+                    session.refresh(&challenge);
+                    return session.authorization(method, uri);
+                }
+            }
+
+            let credentials = self.credentials(exchange, proxy_uri, &challenges).await?;
+            let mut session = digest::Session::new(credentials, &challenge);
+            let authorization = session.authorization(method, uri)?;
+            *self.digest.lock().unwrap() = Some(session);
+            return Ok(authorization);
+        }
+
+        let basic = challenges.is_empty()
+            || challenges
+                .iter()
+                .any(|c| c.scheme.eq_ignore_ascii_case("basic"));
+        if !basic {
+            let schemes = challenges.iter().map(|c| c.scheme.clone()).collect();
+            return Err(Error::UnsupportedProxyAuthentication(schemes));
+        }
+
+        let credentials = self.credentials(exchange, proxy_uri, &challenges).await?;
+        Ok(
+            Authorization::basic(&credentials.username, &credentials.password)
+                .0
+                .encode(),
+        )
+    }
+
+    /// Answers with the first [`ProxyAuthenticator`] whose scheme is challenged, continuing the
+    /// exchange of the connection if it has one
+    fn respond_custom(
+        &self,
+        exchange: &mut Exchange,
+        proxy_uri: &Uri,
+        challenges: &[Challenge],
+        target: &str,
+    ) -> Result<Option<HeaderValue>, Error> {
+        let found = self.authenticators.iter().find_map(|authenticator| {
+            challenges
+                .iter()
+                .find(|c| c.scheme.eq_ignore_ascii_case(authenticator.scheme()))
+                .map(|challenge| (authenticator, challenge))
+        });
+        let (authenticator, challenge) = match found {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+
+        // a challenge without token rejects the previous exchange, if any
+        let continued = challenge.token68.is_some()
+            && matches!(&exchange.context, Some((scheme, _)) if scheme.eq_ignore_ascii_case(&challenge.scheme));
+        let mut challenge = challenge.clone();
+        if !continued {
+            self.next_round(exchange)?;
+            let context = authenticator.start(proxy_uri, target)?;
+            exchange.context = Some((challenge.scheme.clone(), context));
+            // the token of the proxy belongs to the exchange of a closed connection
+            challenge.token68 = None;
+        }
+        let (scheme, context) = exchange.context.as_mut().unwrap();
+        match context.next_token(&challenge)? {
+            Some(token) => header(format!("{} {}", scheme, token)).map(Some),
+            None => Err(Error::ProxyAuthenticationRequired),
+        }
+    }
+
+    /// Counts a new round of authentication, failing after the maximum number of retries
+    fn next_round(&self, exchange: &mut Exchange) -> Result<usize, Error> {
+        exchange.rounds += 1;
+        if exchange.rounds > self.max_retries {
+            return Err(Error::ProxyAuthenticationRequired);
+        }
+        Ok(exchange.rounds)
+    }
+
+    /// The static credentials are tried first, then the ones of the provider
+    async fn credentials(
+        &self,
+        exchange: &mut Exchange,
+        proxy_uri: &Uri,
+        challenges: &[Challenge],
+    ) -> Result<ProxyCredentials, Error> {
+        let mut attempt = self.next_round(exchange)?;
+        if let Some(credentials) = &self.credentials {
+            if attempt == 1 {
+                return Ok(credentials.clone());
+            }
+            attempt -= 1;
+        }
+
+        let provider = self
+            .provider
+            .as_ref()
+            .ok_or(Error::ProxyAuthenticationRequired)?;
+        let challenge = ProxyChallenge {
+            proxy_uri: proxy_uri.clone(),
+            challenges: challenges.to_vec(),
+            attempt,
+        };
+        (provider.0)(challenge)
+            .await
+            .ok_or(Error::ProxyAuthenticationRequired)
+    }
+}
+
+fn header(value: String) -> Result<HeaderValue, Error> {
+    HeaderValue::from_str(&value).map_err(|e| Error::Http(e.into()))
+}
+
+/// The state of the authentication of a single request, which may span several connections
+#[derive(Default)]
+pub(crate) struct Exchange {
+    /// The number of times credentials were sent
+    rounds: usize,
+    /// Whether the connection is kept during the exchange, as connection-based schemes need
+    connection_based: bool,
+    /// The NTLM negotiation started on the current connection
+    ntlm: Option<ntlm::Negotiation>,
+    /// The [`ProxyAuthenticator`] exchange started on the current connection, with its scheme
+    context: Option<(String, Box<dyn ProxyAuthContext>)>,
+}
+
+impl Exchange {
+    pub(crate) fn new(connection_based: bool) -> Exchange {
+        Exchange {
+            connection_based,
+            ..Exchange::default()
+        }
+    }
+
+    /// Forgets the state bound to the previous connection
+    pub(crate) fn reconnected(&mut self) {
+        self.ntlm = None;
+        self.context = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(value: &'static str) -> Vec<Challenge> {
+        let mut headers = HeaderMap::new();
+        headers.insert(PROXY_AUTHENTICATE, HeaderValue::from_static(value));
+        Challenge::parse_all(&headers)
+    }
+
+    fn challenge(scheme: &str, token68: Option<&str>, params: &[(&str, &str)]) -> Challenge {
+        Challenge {
+            scheme: scheme.into(),
+            token68: token68.map(Into::into),
+            params: params
+                .iter()
+                .map(|(n, v)| (n.to_string(), v.to_string()))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_parse_challenges() {
+        assert_eq!(
+            parse(r#"Basic realm="corp \"proxy\"", charset="UTF-8""#),
+            vec![challenge(
+                "Basic",
+                None,
+                &[("realm", r#"corp "proxy""#), ("charset", "UTF-8")]
+            )]
+        );
+        assert_eq!(
+            parse(
+                r#"Negotiate, NTLM TlRMTVNTUAACAAAA==, Digest realm=proxy, qop="auth,auth-int", nonce="abc""#
+            ),
+            vec![
+                challenge("Negotiate", None, &[]),
+                challenge("NTLM", Some("TlRMTVNTUAACAAAA=="), &[]),
+                challenge(
+                    "Digest",
+                    None,
+                    &[
+                        ("realm", "proxy"),
+                        ("qop", "auth,auth-int"),
+                        ("nonce", "abc")
+                    ]
+                ),
+            ]
+        );
+        assert_eq!(parse("Basic realm=\"unterminated"), vec![]);
+        assert_eq!(parse(r#"Basic realm="a""#)[0].realm(), Some("a"));
+    }
+}
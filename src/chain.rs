@@ -0,0 +1,425 @@
+//! Tunneling through several proxies in sequence
+
+use std::sync::Arc;
+use std::time::Instant;
+
+use futures_util::future::TryFutureExt;
+use http::uri::Scheme;
+use hyper::{rt, Uri};
+use hyper_util::{client::legacy::connect::Connection, rt::TokioIo};
+use tower_service::Service;
+
+use crate::socks::{self, SocksVersion};
+use crate::stream::{ChainIo, ChainedStream};
+use crate::{
+    tunnel, ConnectFuture, Error, Handshake, Intercept, Proxy, ProxyInfo, ProxyMode, ProxyStream,
+    ProxyStreamKind, TimeoutPhase, Timeouts, TlsConnect,
+};
+
+/// A sequence of proxies, each one tunneling to the next
+///
+/// Each hop is a [`Proxy`] whose uri scheme chooses the handshake used to reach the next hop,
+/// or the target for the last one: a CONNECT request for `http://` and `https://` proxies (the
+/// latter over TLS, see [`ProxyConnector::set_proxy_tls`](crate::ProxyConnector::set_proxy_tls)),
+/// or a SOCKS handshake. The headers and credentials of each hop are only sent to that hop, and
+/// their intercepts are ignored. The timeouts of each hop apply to its handshakes, and the TCP
+/// connect timeout to the first hop only; the TLS handshake with the target uses the timeout of
+/// the last hop. Errors are wrapped in [`Error::ProxyChain`], giving the hop
+/// which failed.
+///
+/// # Example
+/// ```rust
+/// use hyper_proxy::{Intercept, Proxy, ProxyChain, ProxyCredentials};
+///
+/// let egress = Proxy::new(Intercept::All, "http://egress:3128".parse().unwrap());
+/// let mut regional = Proxy::new(Intercept::All, "http://regional:3128".parse().unwrap());
+/// regional.set_credentials(ProxyCredentials::new("john", "secret"));
+/// let chain = ProxyChain::new(Intercept::All, vec![egress, regional]);
+/// ```
+#[derive(Debug, Clone)]
+pub struct ProxyChain {
+    intercept: Intercept,
+    hops: Arc<[Proxy]>,
+}
+
+impl ProxyChain {
+    /// Create a new chain, the connections going through `hops` in order
+    pub fn new<I, H>(intercept: I, hops: H) -> ProxyChain
+    where
+        I: Into<Intercept>,
+        H: IntoIterator<Item = Proxy>,
+    {
+        ProxyChain {
+            intercept: intercept.into(),
+            hops: hops.into_iter().collect(),
+        }
+    }
+
+    /// Get current intercept
+    pub fn intercept(&self) -> &Intercept {
+        &self.intercept
+    }
+
+    /// Get the hops of the chain
+    pub fn hops(&self) -> &[Proxy] {
+        &self.hops
+    }
+}
+
+/// Connects to `target_uri` through all the hops of `chain`, or directly if it has none
+pub(crate) fn connect<C>(
+    connector: C,
+    tls: &Option<Arc<dyn TlsConnect>>,
+    proxy_tls: &Option<Arc<dyn TlsConnect>>,
+    timeouts: &Timeouts,
+    chain: &ProxyChain,
+    target_uri: Uri,
+) -> ConnectFuture<C::Response>
+where
+    C: Service<Uri> + Clone + Send + 'static,
+    C::Response: Connection + rt::Read + rt::Write + Send + Unpin + 'static,
+    C::Future: Send + 'static,
+    C::Error: Into<Error>,
+{
+    // the timeouts of the connector apply to the hops without their own
+    let hops: Arc<[Proxy]> = chain
+        .hops
+        .iter()
+        .map(|hop| {
+            let mut hop = hop.clone();
+            hop.timeouts = hop.timeouts.or(timeouts);
+            hop
+        })
+        .collect();
+    let proxy_tls = proxy_tls.clone();
+    let tls = if target_uri.scheme() == Some(&Scheme::HTTPS) {
+        tls.clone()
+    } else {
+        None
+    };
+
+    Box::pin(async move {
+        let started = Instant::now();
+        if hops.is_empty() {
+            let mut connector = connector;
+            crate::ready(&mut connector).await?;
+            let stream = connector.call(target_uri).await.map_err(Into::into)?;
+            let mut info = ProxyInfo::direct();
+            info.set_handshake_duration(started.elapsed());
+            return Ok(ProxyStream::new(ProxyStreamKind::NoProxy(stream), info));
+        }
+
+        let target_host = target_uri
+            .host()
+            .ok_or_else(|| Error::MissingUriHost(target_uri.clone()))?;
+        let port = target_uri
+            .port_u16()
+            .unwrap_or(if target_uri.scheme() == Some(&Scheme::HTTP) {
+                80
+            } else {
+                443
+            });
+        let last = hops.len() - 1;
+        let stream = open(connector.clone(), proxy_tls.clone(), hops.clone(), last).await?;
+        let reconnect = reconnect(connector, proxy_tls, hops.clone(), last);
+        let tunnel =
+            Handshake::new(&hops[last], target_host, port).with_stream(stream, Some(reconnect));
+        let (stream, connect_response) = hops[last]
+            .timeouts
+            .run(TimeoutPhase::Handshake, &hops[last].uri, tunnel)
+            .await
+            .map_err(|e| hop_error(&hops, last, e))?;
+        let stream: Box<dyn ChainIo> = Box::new(stream);
+
+        let first = crate::without_credentials(&hops[0].uri);
+        let mut info = ProxyInfo::new(Some(first), ProxyMode::Chain);
+        info.set_tls(hops.iter().any(is_secured), tls.is_some());
+        let stream: Box<dyn ChainIo> = match tls {
+            Some(tls) => {
+                let stream = crate::secure_target(&tls, target_host, stream);
+                let timeouts = &hops[last].timeouts;
+                let stream = timeouts
+                    .run(TimeoutPhase::TargetTlsHandshake, &hops[last].uri, stream)
+                    .await?;
+                info.set_alpn_protocol(stream.alpn_protocol());
+                Box::new(stream)
+            }
+            None => stream,
+        };
+        let kind = ProxyStreamKind::Chained(ChainedStream::new(stream));
+        info.set_handshake_duration(started.elapsed());
+        let mut stream = ProxyStream::new(kind, info);
+        // the response of the last hop, tunneling to the target
+        stream.set_connect_response(connect_response);
+        Ok(stream)
+    })
+}
+
+/// Opens a connection to the hop `index` of the chain, tunneled through the previous hops
+///
+/// The handshakes with the previous hops may reconnect if a proxy closes the connection while
+/// authenticating, each time tunneling again through the hops before it.
+async fn open<C>(
+    mut connector: C,
+    proxy_tls: Option<Arc<dyn TlsConnect>>,
+    hops: Arc<[Proxy]>,
+    index: usize,
+) -> Result<Box<dyn ChainIo>, Error>
+where
+    C: Service<Uri> + Clone + Send + 'static,
+    C::Response: Connection + rt::Read + rt::Write + Send + Unpin + 'static,
+    C::Future: Send + 'static,
+    C::Error: Into<Error>,
+{
+    let first = &hops[0];
+    let connection = async {
+        crate::ready(&mut connector).await?;
+        let dst = crate::tcp_dst(&first.uri, default_port(first))?;
+        let connection = connector.call(dst).map_err(Into::into);
+        first
+            .timeouts
+            .run(TimeoutPhase::Connect, &first.uri, connection)
+            .await
+    };
+    let stream = connection.await.map_err(|e| hop_error(&hops, 0, e))?;
+    let mut stream = secure_hop(Box::new(TokioIo::new(stream)), first, &proxy_tls)
+        .await
+        .map_err(|e| hop_error(&hops, 0, e))?;
+
+    for hop in 0..index {
+        let next = &hops[hop + 1];
+        let host = next
+            .uri
+            .host()
+            .ok_or_else(|| Error::MissingUriHost(next.uri.clone()))?;
+        let reconnect = reconnect(connector.clone(), proxy_tls.clone(), hops.clone(), hop);
+        let tunnel = Handshake::new(
+            &hops[hop],
+            host,
+            next.uri.port_u16().unwrap_or(default_port(next)),
+        )
+        .with_stream(stream, Some(reconnect));
+        let (tunnel_stream, _) = hops[hop]
+            .timeouts
+            .run(TimeoutPhase::Handshake, &hops[hop].uri, tunnel)
+            .await
+            .map_err(|e| hop_error(&hops, hop, e))?;
+        stream = secure_hop(Box::new(tunnel_stream), next, &proxy_tls)
+            .await
+            .map_err(|e| hop_error(&hops, hop + 1, e))?;
+    }
+    Ok(stream)
+}
+
+/// Opens new connections to the hop `index`, for proxies closing the connection while
+/// authenticating
+fn reconnect<C>(
+    connector: C,
+    proxy_tls: Option<Arc<dyn TlsConnect>>,
+    hops: Arc<[Proxy]>,
+    index: usize,
+) -> tunnel::Reconnect<Box<dyn ChainIo>>
+where
+    C: Service<Uri> + Clone + Send + 'static,
+    C::Response: Connection + rt::Read + rt::Write + Send + Unpin + 'static,
+    C::Future: Send + 'static,
+    C::Error: Into<Error>,
+{
+    Box::new(move || {
+        let proxy_tls = proxy_tls.clone();
+        Box::pin(open(connector.clone(), proxy_tls, hops.clone(), index))
+    })
+}
+
+/// Secures the connection to an `https://` hop with TLS
+async fn secure_hop(
+    stream: Box<dyn ChainIo>,
+    proxy: &Proxy,
+    proxy_tls: &Option<Arc<dyn TlsConnect>>,
+) -> Result<Box<dyn ChainIo>, Error> {
+    if !is_secured(proxy) {
+        return Ok(stream);
+    }
+
+    match proxy_tls {
+        Some(tls) => {
+            let host = proxy.uri.host().unwrap_or_default();
+            let stream = crate::secure(tls, host, stream);
+            let timeouts = &proxy.timeouts;
+            Ok(Box::new(
+                timeouts
+                    .run(TimeoutPhase::TlsHandshake, &proxy.uri, stream)
+                    .await?,
+            ))
+        }
+        None => Err(Error::MissingProxyTls(proxy.uri.clone())),
+    }
+}
+
+/// Returns true for the `https://` hops, secured with TLS
+fn is_secured(proxy: &Proxy) -> bool {
+    SocksVersion::from_uri(&proxy.uri).is_none() && proxy.uri.scheme() == Some(&Scheme::HTTPS)
+}
+
+fn default_port(proxy: &Proxy) -> u16 {
+    if SocksVersion::from_uri(&proxy.uri).is_some() {
+        socks::DEFAULT_PORT
+    } else if proxy.uri.scheme() == Some(&Scheme::HTTPS) {
+        443
+    } else {
+        80
+    }
+}
+
+fn hop_error(hops: &[Proxy], hop: usize, error: Error) -> Error {
+    Error::ProxyChain {
+        hop,
+        proxy_uri: hops[hop].uri.clone(),
+        source: Box::new(error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpListener};
+    use std::thread;
+
+    use http::{HeaderName, HeaderValue};
+    use hyper_util::client::legacy::connect::HttpConnector;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::runtime::Runtime;
+    use tower_service::Service;
+
+    use super::ProxyChain;
+    use crate::{BoxConnector, Error, Intercept, Proxy, ProxyConnector};
+
+    /// Runs a proxy playing all the hops of a chain on a single connection, the tunnel to the
+    /// next hop being faked
+    fn mock_chain(exchange: Vec<(Vec<u8>, Vec<u8>)>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        thread::spawn(move || {
+            let (mut sock, _) = listener.accept().unwrap();
+            for (expected, reply) in exchange {
+                let mut buf = vec![0u8; expected.len()];
+                sock.read_exact(&mut buf).unwrap();
+                assert_eq!(
+                    String::from_utf8_lossy(&buf),
+                    String::from_utf8_lossy(&expected)
+                );
+                sock.write_all(&reply).unwrap();
+            }
+        });
+        addr
+    }
+
+    fn hop(uri: &str, name: &'static str) -> Proxy {
+        let mut proxy = Proxy::new(Intercept::All, uri.parse().unwrap());
+        proxy.set_header(
+            HeaderName::from_static("x-hop"),
+            HeaderValue::from_static(name),
+        );
+        proxy
+    }
+
+    fn connector(chain: ProxyChain) -> ProxyConnector<BoxConnector<HttpConnector>> {
+        let mut connector = ProxyConnector::unsecured(BoxConnector(HttpConnector::new()));
+        connector.add_chain(chain);
+        connector
+    }
+
+    #[test]
+    fn test_chain() {
+        let addr = mock_chain(vec![
+            (
+                b"CONNECT regional.example:1080 HTTP/1.1\r\n\
+                Host: regional.example:1080\r\n\
+                x-hop: egress\r\n\
+                \r\n"
+                    .to_vec(),
+                b"HTTP/1.1 200 OK\r\n\r\n".to_vec(),
+            ),
+            (vec![5, 1, 0], vec![5, 0]),
+            (
+                b"\x05\x01\x00\x03\x0clast.example\x0c\x38".to_vec(),
+                vec![5, 0, 0, 1, 127, 0, 0, 1, 0, 80],
+            ),
+            (
+                b"CONNECT target.example:80 HTTP/1.1\r\n\
+                Host: target.example:80\r\n\
+                x-hop: last\r\n\
+                \r\n"
+                    .to_vec(),
+                b"HTTP/1.1 200 OK\r\n\r\n".to_vec(),
+            ),
+            (b"ping".to_vec(), b"pong".to_vec()),
+        ]);
+        let chain = ProxyChain::new(
+            Intercept::All,
+            vec![
+                hop(&format!("http://{}", addr), "egress"),
+                hop("socks5h://regional.example", "regional"),
+                hop("http://last.example:3128", "last"),
+            ],
+        );
+
+        Runtime::new().unwrap().block_on(async {
+            let uri = "http://target.example/".parse().unwrap();
+            let stream = connector(chain).call(uri).await.unwrap();
+            let mut stream = hyper_util::rt::TokioIo::new(stream);
+            stream.write_all(b"ping").await.unwrap();
+            let mut buf = [0; 4];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"pong");
+        });
+    }
+
+    #[test]
+    fn test_chain_hop_error() {
+        let addr = mock_chain(vec![
+            (
+                b"CONNECT regional.example:80 HTTP/1.1\r\n\
+                Host: regional.example:80\r\n\
+                x-hop: egress\r\n\
+                \r\n"
+                    .to_vec(),
+                b"HTTP/1.1 200 OK\r\n\r\n".to_vec(),
+            ),
+            (
+                b"CONNECT target.example:443 HTTP/1.1\r\n\
+                Host: target.example:443\r\n\
+                x-hop: regional\r\n\
+                \r\n"
+                    .to_vec(),
+                b"HTTP/1.1 403 Forbidden\r\n\r\n".to_vec(),
+            ),
+        ]);
+        let chain = ProxyChain::new(
+            Intercept::All,
+            vec![
+                hop(&format!("http://{}", addr), "egress"),
+                hop("http://regional.example", "regional"),
+            ],
+        );
+
+        let result = Runtime::new().unwrap().block_on(async {
+            let uri = "http://target.example:443/".parse().unwrap();
+            connector(chain).call(uri).await
+        });
+        match result {
+            Err(Error::ProxyChain {
+                hop,
+                proxy_uri,
+                source,
+            }) => {
+                assert_eq!(hop, 1);
+                assert_eq!(proxy_uri, "http://regional.example");
+                assert!(matches!(*source, Error::UnsuccessfulTunnel(_)));
+            }
+            Err(err) => panic!("unexpected error {:?}", err),
+            Ok(_) => panic!("unexpected tunnel"),
+        }
+    }
+}
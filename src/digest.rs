@@ -0,0 +1,317 @@
+//! HTTP Digest access authentication (RFC 7616)
+
+use std::fmt::Write;
+
+use http::HeaderValue;
+use md5::Md5;
+use sha2::{Digest, Sha256};
+
+use crate::auth::{Challenge, ProxyCredentials};
+use crate::Error;
+
+/// The hash algorithms supported, by order of preference
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Algorithm {
+    Sha256Sess,
+    Sha256,
+    Md5Sess,
+    Md5,
+}
+
+impl Algorithm {
+    fn parse(name: Option<&str>) -> Option<Algorithm> {
+        // MD5 is the default when the algorithm is not specified
+        match name.map(str::to_ascii_uppercase).as_deref() {
+            None | Some("MD5") => Some(Algorithm::Md5),
+            Some("MD5-SESS") => Some(Algorithm::Md5Sess),
+            Some("SHA-256") => Some(Algorithm::Sha256),
+            Some("SHA-256-SESS") => Some(Algorithm::Sha256Sess),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Algorithm::Md5 => "MD5",
+            Algorithm::Md5Sess => "MD5-sess",
+            Algorithm::Sha256 => "SHA-256",
+            Algorithm::Sha256Sess => "SHA-256-sess",
+        }
+    }
+
+    fn hash(self, data: &str) -> String {
+        let bytes = match self {
+            Algorithm::Md5 | Algorithm::Md5Sess => Md5::digest(data.as_bytes()).to_vec(),
+            Algorithm::Sha256 | Algorithm::Sha256Sess => Sha256::digest(data.as_bytes()).to_vec(),
+        };
+        bytes.iter().fold(String::new(), |mut s, b| {
+            let _ = write!(s, "{:02x}", b);
+            s
+        })
+    }
+
+    fn is_session(self) -> bool {
+        matches!(self, Algorithm::Md5Sess | Algorithm::Sha256Sess)
+    }
+}
+
+/// Returns the supported Digest challenge to answer, preferring the strongest algorithm
+pub(crate) fn select(challenges: &[Challenge]) -> Option<&Challenge> {
+    challenges
+        .iter()
+        .filter(|c| c.scheme().eq_ignore_ascii_case("digest") && c.param("nonce").is_some())
+        .filter(|c| match c.param("qop") {
+            Some(qop) => qop.split(',').any(|q| q.trim() == "auth"),
+            None => true,
+        })
+        .filter_map(|c| Algorithm::parse(c.param("algorithm")).map(|a| (a, c)))
+        .min_by_key(|(a, _)| *a as u8)
+        .map(|(_, c)| c)
+}
+
+/// Returns true if the challenge only reports an expired nonce, the credentials being valid
+pub(crate) fn is_stale(challenge: &Challenge) -> bool {
+    challenge
+        .param("stale")
+        .is_some_and(|s| s.eq_ignore_ascii_case("true"))
+}
+
+/// The state of a Digest authentication with a proxy: the last challenge, and the number of
+/// requests already made with its nonce
+#[derive(Debug, Clone)]
+pub(crate) struct Session {
+    credentials: ProxyCredentials,
+    algorithm: Algorithm,
+    realm: String,
+    nonce: String,
+    opaque: Option<String>,
+    qop: bool,
+    nc: u32,
+}
+
+impl Session {
+    /// Starts a session answering a challenge returned by [`select`]
+    pub(crate) fn new(credentials: ProxyCredentials, challenge: &Challenge) -> Session {
+        Session {
+            credentials,
+            algorithm: Algorithm::parse(challenge.param("algorithm")).unwrap_or(Algorithm::Md5),
+            realm: challenge.realm().unwrap_or_default().to_owned(),
+            nonce: challenge.param("nonce").unwrap_or_default().to_owned(),
+            opaque: challenge.param("opaque").map(String::from),
+            qop: challenge.param("qop").is_some(),
+            nc: 0,
+        }
+    }
+
+    /// Computes the `Proxy-Authorization` header of the next request
+    pub(crate) fn authorization(&mut self, method: &str, uri: &str) -> Result<HeaderValue, Error> {
+        self.nc += 1;
+        let nc = format!("{:08x}", self.nc);
+        let cnonce = cnonce()?;
+        self.authorization_with(method, uri, &nc, &cnonce)
+    }
+
+    fn authorization_with(
+        &self,
+        method: &str,
+        uri: &str,
+        nc: &str,
+        cnonce: &str,
+    ) -> Result<HeaderValue, Error> {
+        let a = self.algorithm;
+        let mut ha1 = a.hash(&format!(
+            "{}:{}:{}",
+            self.credentials.username(),
+            self.realm,
+            self.credentials.password()
+        ));
+        if a.is_session() {
+            ha1 = a.hash(&format!("{}:{}:{}", ha1, self.nonce, cnonce));
+        }
+        let ha2 = a.hash(&format!("{}:{}", method, uri));
+        let response = if self.qop {
+            a.hash(&format!(
+                "{}:{}:{}:{}:auth:{}",
+                ha1, self.nonce, nc, cnonce, ha2
+            ))
+        } else {
+            a.hash(&format!("{}:{}:{}", ha1, self.nonce, ha2))
+        };
+
+        let username = self.credentials.username();
+        let username = if username.is_ascii() {
+            format!("username=\"{}\"", quote(username))
+        } else {
+            // RFC 7616 section 3.4.4
+            format!("username*=UTF-8''{}", encode_ext_value(username))
+        };
+        let mut header = format!(
+            "Digest {}, realm=\"{}\", nonce=\"{}\", uri=\"{}\", algorithm={}, response=\"{}\"",
+            username,
+            quote(&self.realm),
+            quote(&self.nonce),
+            quote(uri),
+            a.name(),
+            response
+        );
+        if self.qop {
+            let _ = write!(header, ", qop=auth, nc={}, cnonce=\"{}\"", nc, cnonce);
+        }
+        if let Some(opaque) = &self.opaque {
+            let _ = write!(header, ", opaque=\"{}\"", quote(opaque));
+        }
+        HeaderValue::from_str(&header).map_err(|e| Error::Http(e.into()))
+    }
+
+    /// Continues the session with the new nonce of a stale challenge
+    pub(crate) fn refresh(&mut self, challenge: &Challenge) {
+        *self = Session::new(self.credentials.clone(), challenge);
+    }
+}
+
+fn quote(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Percent-encodes `s` as the value of an RFC 8187 extended parameter
+fn encode_ext_value(s: &str) -> String {
+    s.bytes().fold(String::new(), |mut encoded, b| {
+        if b.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&b) {
+            encoded.push(b as char);
+        } else {
+            let _ = write!(encoded, "%{:02X}", b);
+        }
+        encoded
+    })
+}
+
+fn cnonce() -> Result<String, Error> {
+    let mut bytes = [0; 16];
+    getrandom::getrandom(&mut bytes)
+        .map_err(|e| Error::Io(std::io::Error::other(e.to_string())))?;
+    Ok(bytes.iter().fold(String::new(), |mut s, b| {
+        let _ = write!(s, "{:02x}", b);
+        s
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::header::{HeaderMap, PROXY_AUTHENTICATE};
+
+    fn challenges(value: &'static str) -> Vec<Challenge> {
+        let mut headers = HeaderMap::new();
+        headers.insert(PROXY_AUTHENTICATE, HeaderValue::from_static(value));
+        Challenge::parse_all(&headers)
+    }
+
+    #[test]
+    fn test_rfc7616_md5() {
+        // example of RFC 7616 section 3.9.1
+        let challenges = challenges(concat!(
+            r#"Digest realm="http-auth@example.org", qop="auth, auth-int", algorithm=MD5, "#,
+            r#"nonce="7ypf/xlj9XXwfDPEoM4URrv/xwf94BcCAzFZH4GiTo0v", "#,
+            r#"opaque="FQhe/qaU925kfnzjCev0ciny7QMkPqMAFRtzCUYo5tdS""#,
+        ));
+        let credentials = ProxyCredentials::new("Mufasa", "Circle of Life");
+        let session = Session::new(credentials, select(&challenges).unwrap());
+        let header = session
+            .authorization_with(
+                "GET",
+                "/dir/index.html",
+                "00000001",
+                "f2/wE4q74E6zIJEtWaHKaf5wv/H5QzzpXusqGemxURZJ",
+            )
+            .unwrap();
+        assert!(header
+            .to_str()
+            .unwrap()
+            .contains("response=\"8ca523f5e9506fed4657c9700eebdbec\""));
+    }
+
+    #[test]
+    fn test_rfc7616_sha256() {
+        // example of RFC 7616 section 3.9.1, with both algorithms offered
+        let challenges = challenges(concat!(
+            r#"Digest realm="http-auth@example.org", qop="auth, auth-int", algorithm=MD5, "#,
+            r#"nonce="7ypf/xlj9XXwfDPEoM4URrv/xwf94BcCAzFZH4GiTo0v", "#,
+            r#"opaque="FQhe/qaU925kfnzjCev0ciny7QMkPqMAFRtzCUYo5tdS", "#,
+            r#"Digest realm="http-auth@example.org", qop="auth, auth-int", algorithm=SHA-256, "#,
+            r#"nonce="7ypf/xlj9XXwfDPEoM4URrv/xwf94BcCAzFZH4GiTo0v", "#,
+            r#"opaque="FQhe/qaU925kfnzjCev0ciny7QMkPqMAFRtzCUYo5tdS""#,
+        ));
+        let credentials = ProxyCredentials::new("Mufasa", "Circle of Life");
+        let session = Session::new(credentials, select(&challenges).unwrap());
+        let header = session
+            .authorization_with(
+                "GET",
+                "/dir/index.html",
+                "00000001",
+                "f2/wE4q74E6zIJEtWaHKaf5wv/H5QzzpXusqGemxURZJ",
+            )
+            .unwrap();
+        let header = header.to_str().unwrap();
+        assert!(header.contains("algorithm=SHA-256,"));
+        assert!(header.contains(
+            "response=\"753927fa0e85d155564e2e272a28d1802ca10daf4496794697cf8db5856cb6c1\""
+        ));
+        assert!(header.contains("opaque=\"FQhe/qaU925kfnzjCev0ciny7QMkPqMAFRtzCUYo5tdS\""));
+    }
+
+    #[test]
+    fn test_nonce_count() {
+        let challenges = challenges(r#"Digest realm="proxy", nonce="abc", qop="auth""#);
+        let mut session = Session::new(
+            ProxyCredentials::new("john", "secret"),
+            select(&challenges).unwrap(),
+        );
+        let first = session.authorization("CONNECT", "example.org:443").unwrap();
+        let second = session.authorization("CONNECT", "example.org:443").unwrap();
+        assert!(first.to_str().unwrap().contains("nc=00000001"));
+        assert!(second.to_str().unwrap().contains("nc=00000002"));
+
+        let stale = challenges_stale();
+        assert!(is_stale(&stale[0]));
+        session.refresh(&stale[0]);
+        let third = session.authorization("CONNECT", "example.org:443").unwrap();
+        assert!(third.to_str().unwrap().contains("nonce=\"def\""));
+        assert!(third.to_str().unwrap().contains("nc=00000001"));
+    }
+
+    #[test]
+    fn test_non_ascii_username() {
+        let challenges = challenges(r#"Digest realm="p", nonce="n", qop="auth""#);
+        let session = Session::new(
+            ProxyCredentials::new("josé n", "pw"),
+            select(&challenges).unwrap(),
+        );
+        let header = session
+            .authorization_with("CONNECT", "example.org:443", "00000001", "c")
+            .unwrap();
+        let header = header.to_str().unwrap();
+        assert!(header.starts_with("Digest username*=UTF-8''jos%C3%A9%20n, realm=\"p\","));
+        // the response hashes the username itself
+        let ha1 = Algorithm::Md5.hash("josé n:p:pw");
+        let ha2 = Algorithm::Md5.hash("CONNECT:example.org:443");
+        let response = Algorithm::Md5.hash(&format!("{}:n:00000001:c:auth:{}", ha1, ha2));
+        assert!(header.contains(&format!("response=\"{}\"", response)));
+    }
+
+    fn challenges_stale() -> Vec<Challenge> {
+        challenges(r#"Digest realm="proxy", nonce="def", qop="auth", stale=true"#)
+    }
+
+    #[test]
+    fn test_select_unsupported() {
+        assert!(select(&challenges(
+            r#"Digest realm="p", nonce="a", algorithm=SHA-512-256"#
+        ))
+        .is_none());
+        assert!(select(&challenges(
+            r#"Digest realm="p", nonce="a", qop="auth-int""#
+        ))
+        .is_none());
+        assert!(select(&challenges(r#"Basic realm="p""#)).is_none());
+    }
+}
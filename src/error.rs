@@ -15,14 +15,26 @@ pub enum Error {
     #[error("unsuccessful tunnel ({0})")]
     UnsuccessfulTunnel(String),
 
+    #[error("proxy response head exceeds {0} bytes")]
+    ProxyResponseTooLarge(usize),
+
+    #[error("proxy response has more than {0} headers")]
+    TooManyProxyResponseHeaders(usize),
+
     #[error("Proxy Authentication Required, please set the credentials and retry")]
     ProxyAuthenticationRequired,
 
+    #[error("Proxy requires authentication with unsupported schemes: {0:?}")]
+    UnsupportedProxyAuthentication(Vec<String>),
+
+    #[error("invalid NTLM challenge from the proxy: {0}")]
+    InvalidNtlmChallenge(String),
+
     #[error("Proxy {proxy_uri} is redirecting to {location} (status {status_code})")]
     ProxyRedirect {
         status_code: u16,
-        location: Uri,
-        proxy_uri: Uri,
+        location: Box<Uri>,
+        proxy_uri: Box<Uri>,
     },
 
     #[error("Proxy is redirecting ({code}), but no location provided")]
@@ -34,6 +46,82 @@ pub enum Error {
     #[error("proxy uri missing host: {0}")]
     MissingUriScheme(Uri),
 
+    #[error("invalid SOCKS response ({0})")]
+    InvalidSocksResponse(String),
+
+    #[error("SOCKS username and password must not exceed 255 bytes")]
+    InvalidSocksCredentials,
+
+    #[error("SOCKS target host is too long: {0}")]
+    InvalidSocksTarget(String),
+
+    #[error("SOCKS proxy accepts none of the offered authentication methods")]
+    SocksNoAcceptableAuthMethod,
+
+    #[error("SOCKS proxy rejected the credentials")]
+    SocksAuthenticationFailed,
+
+    #[error("SOCKS4 proxy rejected the request or the request failed")]
+    Socks4RequestRejected,
+
+    #[error("SOCKS4 proxy rejected the request because it cannot reach the client identd")]
+    Socks4IdentdUnreachable,
+
+    #[error("SOCKS4 proxy rejected the request because identd reported a different user id")]
+    Socks4IdentdMismatch,
+
+    #[error("SOCKS5 proxy failed to connect to the target: {reason} (code {code})")]
+    Socks5ConnectFailed { code: u8, reason: &'static str },
+
+    #[error("proxy chain hop {hop} ({proxy_uri}) failed: {source}")]
+    ProxyChain {
+        /// The index of the failing hop in the chain
+        hop: usize,
+        proxy_uri: Uri,
+        source: Box<Error>,
+    },
+
+    #[error("{phase} with proxy {proxy_uri} timed out")]
+    Timeout {
+        phase: crate::TimeoutPhase,
+        proxy_uri: Uri,
+    },
+
+    #[error("TLS handshake with the target {host} failed: {source}")]
+    TargetTls { host: String, source: Box<Error> },
+
+    #[error("no proxy chosen for {0} shares the headers of the proxy forwarding plain http")]
+    ProxyHeadersNotShared(Uri),
+
+    #[error("no TLS configuration to connect to the https proxy {0}")]
+    MissingProxyTls(Uri),
+
+    #[error("invalid ALPN protocol {0:?}, it must be 1 to 255 bytes long")]
+    InvalidAlpnProtocol(String),
+
+    #[error("invalid client identity: {0}")]
+    InvalidClientIdentity(String),
+
+    #[error("invalid root certificates: {0}")]
+    InvalidRootCertificates(String),
+
+    #[error("the certificate of {0} matches none of the pinned public keys")]
+    CertificatePinMismatch(String),
+
+    #[error("the certificate of {0} was rejected by the verification callback")]
+    CertificateRejected(String),
+
+    #[error("invalid proxy uri in {name} environment variable: {value}")]
+    InvalidProxyEnv { name: &'static str, value: String },
+
+    #[cfg(feature = "pac")]
+    #[error("PAC script error: {0}")]
+    Pac(String),
+
+    #[cfg(feature = "pac")]
+    #[error("WPAD discovery failed: {0}")]
+    Wpad(String),
+
     #[error("{0}")]
     Http(#[from] http::Error),
 
@@ -0,0 +1,356 @@
+//! Failover across the proxies matching a connection
+
+use std::fmt;
+use std::sync::Arc;
+use std::time::Instant;
+
+use hyper::{rt, Uri};
+use hyper_util::client::legacy::connect::Connection;
+use tower_service::Service;
+
+use crate::affinity::Affinity;
+use crate::health::Health;
+use crate::pool::ProxyPool;
+use crate::{Error, Proxy, ProxyStream, TimeoutPhase, Timeouts, TlsConnect};
+
+/// The failover policy of a [`ProxyConnector`](crate::ProxyConnector)
+///
+/// When the connection to a proxy fails with an error triggering failover, the next proxy
+/// matching the connection is tried, and then a direct connection if enabled. The proxy finally
+/// used is reported by [`ProxyStream::proxy`].
+///
+/// # Example
+/// ```rust
+/// use hyper_proxy::{Error, Failover};
+///
+/// let mut failover = Failover::new();
+/// failover.set_direct(true);
+/// // also fail over when a proxy refuses the tunnel
+/// failover.set_trigger(|e| {
+///     Failover::is_connect_error(e) || matches!(e, Error::ProxyAuthenticationRequired)
+/// });
+/// ```
+#[derive(Clone)]
+pub struct Failover {
+    direct: bool,
+    trigger: Arc<dyn Fn(&Error) -> bool + Send + Sync>,
+}
+
+impl fmt::Debug for Failover {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Failover")
+            .field("direct", &self.direct)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for Failover {
+    fn default() -> Failover {
+        Failover::new()
+    }
+}
+
+impl Failover {
+    /// Create a failover policy triggered by [`Failover::is_connect_error`], without direct
+    /// connection
+    pub fn new() -> Failover {
+        Failover {
+            direct: false,
+            trigger: Arc::new(Failover::is_connect_error),
+        }
+    }
+
+    /// Set whether to connect directly when all the proxies failed
+    pub fn set_direct(&mut self, direct: bool) {
+        self.direct = direct;
+    }
+
+    /// Set the errors triggering failover to the next proxy
+    pub fn set_trigger<F>(&mut self, trigger: F)
+    where
+        F: Fn(&Error) -> bool + Send + Sync + 'static,
+    {
+        self.trigger = Arc::new(trigger);
+    }
+
+    /// Returns true for errors of the TCP connection or of the handshake with the proxy, the
+    /// default trigger
+    ///
+    /// The failures of the TLS handshake with the target, [`Error::TargetTls`] and the
+    /// [`TimeoutPhase::TargetTlsHandshake`] timeouts, are not connect errors: another proxy
+    /// would reach the same target.
+    pub fn is_connect_error(error: &Error) -> bool {
+        if let Error::Timeout { phase, .. } = error {
+            return *phase != TimeoutPhase::TargetTlsHandshake;
+        }
+        matches!(
+            error,
+            Error::Io(_)
+                | Error::Other(_)
+                | Error::UnexpectedEOF
+                | Error::UnsuccessfulTunnel(_)
+                | Error::ProxyResponseTooLarge(_)
+                | Error::TooManyProxyResponseHeaders(_)
+                | Error::InvalidSocksResponse(_)
+                | Error::Socks4RequestRejected
+                | Error::Socks5ConnectFailed { .. }
+        )
+    }
+}
+
+/// Returns the proxies matching `uri`, in the order of the pool, the unhealthy ones last
+pub(crate) fn candidates(proxies: &[Proxy], uri: &Uri, policy: &Policy) -> Vec<Option<Proxy>> {
+    let mut matching: Vec<&Proxy> = proxies
+        .iter()
+        .filter(|p| p.intercept.matches(uri))
+        .collect();
+    if let Some(pool) = &policy.pool {
+        pool.order(&mut matching);
+    }
+    if let Some(health) = &policy.health {
+        matching.sort_by_key(|p| !health.is_healthy(p));
+    }
+    matching.into_iter().cloned().map(Some).collect()
+}
+
+/// How to go through the candidate proxies
+pub(crate) struct Policy {
+    pub(crate) failover: Option<Failover>,
+    pub(crate) health: Option<Health>,
+    pub(crate) pool: Option<ProxyPool>,
+    pub(crate) affinity: Option<Affinity>,
+    pub(crate) timeouts: Timeouts,
+    /// The proxy whose headers plain http requests carry (see `ProxyConnector::http_headers`)
+    pub(crate) forwarding: Option<Proxy>,
+}
+
+/// Connects to `target_uri` through the first candidate that works, `None` being a direct
+/// connection
+pub(crate) async fn connect<C>(
+    mut connector: C,
+    tls: Option<Arc<dyn TlsConnect>>,
+    proxy_tls: Option<Arc<dyn TlsConnect>>,
+    mut candidates: Vec<Option<Proxy>>,
+    policy: Policy,
+    target_uri: Uri,
+) -> Result<ProxyStream<C::Response>, Error>
+where
+    C: Service<Uri> + Clone + Send + 'static,
+    C::Response: Connection + rt::Read + rt::Write + Send + Unpin + 'static,
+    C::Future: Send + 'static,
+    C::Error: Into<Error>,
+{
+    if let Some(affinity) = &policy.affinity {
+        affinity.prefer(&target_uri, &mut candidates, policy.health.as_ref());
+    }
+    if let Some(forwarding) = &policy.forwarding {
+        if target_uri.scheme_str() == Some("http") {
+            let proxies = candidates.len();
+            candidates.retain(|proxy| match proxy {
+                Some(proxy) => !proxy.forwards_http() || forwarding.shares_headers(proxy),
+                None => true,
+            });
+            if proxies > 0 && candidates.is_empty() {
+                return Err(Error::ProxyHeadersNotShared(target_uri));
+            }
+        }
+    }
+    match &policy.failover {
+        Some(failover) if failover.direct && candidates.iter().all(Option::is_some) => {
+            candidates.push(None)
+        }
+        Some(_) => {}
+        None => candidates.truncate(1),
+    }
+
+    let last = candidates.pop().flatten();
+    for proxy in candidates {
+        let result = attempt(
+            &mut connector,
+            &tls,
+            &proxy_tls,
+            proxy,
+            &policy,
+            &target_uri,
+        )
+        .await;
+        match (result, &policy.failover) {
+            (Err(e), Some(failover)) if (failover.trigger)(&e) => {
+                crate::ready(&mut connector).await?
+            }
+            (result, _) => return result,
+        }
+    }
+    attempt(&mut connector, &tls, &proxy_tls, last, &policy, &target_uri).await
+}
+
+/// Connects through `proxy`, recording the result in the health of the proxies, the pool and the affinity
+async fn attempt<C>(
+    connector: &mut C,
+    tls: &Option<Arc<dyn TlsConnect>>,
+    proxy_tls: &Option<Arc<dyn TlsConnect>>,
+    proxy: Option<Proxy>,
+    policy: &Policy,
+    target_uri: &Uri,
+) -> Result<ProxyStream<C::Response>, Error>
+where
+    C: Service<Uri> + Clone + Send + 'static,
+    C::Response: Connection + rt::Read + rt::Write + Send + Unpin + 'static,
+    C::Future: Send + 'static,
+    C::Error: Into<Error>,
+{
+    let outstanding = match (&policy.pool, &proxy) {
+        (Some(pool), Some(proxy)) => Some((pool, pool.start(proxy))),
+        _ => None,
+    };
+    let start = Instant::now();
+    let mut result = crate::connect(
+        connector,
+        tls,
+        proxy_tls,
+        &policy.timeouts,
+        proxy.as_ref(),
+        target_uri.clone(),
+    )
+    .await;
+    if let (Some(health), Some(proxy)) = (&policy.health, &proxy) {
+        health.record(proxy, &result, connector, proxy_tls);
+    }
+    if let (Some((pool, outstanding)), Some(proxy), Ok(stream)) = (outstanding, &proxy, &mut result)
+    {
+        pool.record_latency(proxy, start.elapsed());
+        stream.set_outstanding(outstanding);
+    }
+    if let (Some(affinity), Some(proxy), Ok(_)) = (&policy.affinity, &proxy, &result) {
+        affinity.record(target_uri, proxy);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpListener};
+    use std::thread;
+
+    use hyper_util::client::legacy::connect::HttpConnector;
+    use tokio::runtime::Runtime;
+    use tower_service::Service;
+
+    use super::Failover;
+    use crate::{BoxConnector, Error, Intercept, Proxy, ProxyConnector};
+
+    /// Returns an address nothing listens on
+    fn closed_port() -> SocketAddr {
+        TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+    }
+
+    /// Accepts a connection, answering its first request with `response`
+    fn mock_server(response: &'static [u8]) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut sock, _) = listener.accept().unwrap();
+            let mut buf = [0u8; 4096];
+            let _ = sock.read(&mut buf).unwrap();
+            sock.write_all(response).unwrap();
+        });
+        addr
+    }
+
+    fn connector(
+        proxies: Vec<Proxy>,
+        failover: Option<Failover>,
+    ) -> ProxyConnector<BoxConnector<HttpConnector>> {
+        let mut connector = ProxyConnector::unsecured(BoxConnector(HttpConnector::new()));
+        connector.extend_proxies(proxies);
+        connector.set_failover(failover);
+        connector
+    }
+
+    fn tunneling_proxy(addr: SocketAddr) -> Proxy {
+        let mut proxy = Proxy::new(Intercept::All, format!("http://{}", addr).parse().unwrap());
+        proxy.force_connect();
+        proxy
+    }
+
+    #[test]
+    fn test_failover_next_proxy() {
+        let down = tunneling_proxy(closed_port());
+        let up = mock_server(b"HTTP/1.1 200 OK\r\n\r\n");
+        let uri = format!("http://john:secret@{}", up).parse().unwrap();
+        let mut up_proxy = Proxy::new(Intercept::All, uri);
+        up_proxy.force_connect();
+
+        let mut connector = connector(vec![down, up_proxy], Some(Failover::new()));
+        let stream = Runtime::new()
+            .unwrap()
+            .block_on(connector.call("http://example.org/".parse().unwrap()))
+            .unwrap();
+        // reported without the credentials
+        assert_eq!(
+            stream.proxy().unwrap(),
+            &format!("http://{}", up).parse::<http::Uri>().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_failover_direct() {
+        let target = mock_server(b"");
+        let mut failover = Failover::new();
+        failover.set_direct(true);
+
+        let mut connector = connector(vec![tunneling_proxy(closed_port())], Some(failover));
+        let uri = format!("http://{}/", target).parse().unwrap();
+        let stream = Runtime::new()
+            .unwrap()
+            .block_on(connector.call(uri))
+            .unwrap();
+        assert!(stream.proxy().is_none());
+    }
+
+    #[test]
+    fn test_failover_not_triggered() {
+        let refusing = mock_server(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n");
+        let up = tunneling_proxy(mock_server(b"HTTP/1.1 200 OK\r\n\r\n"));
+
+        let mut connector = connector(vec![tunneling_proxy(refusing), up], Some(Failover::new()));
+        let result = Runtime::new()
+            .unwrap()
+            .block_on(connector.call("http://example.org/".parse().unwrap()));
+        assert!(matches!(result, Err(Error::ProxyAuthenticationRequired)));
+    }
+
+    #[cfg(any(feature = "tls", feature = "rustls-base", feature = "openssl-tls"))]
+    #[test]
+    fn test_failover_not_triggered_by_target_tls() {
+        // the tunnel is established, but the target does not speak TLS
+        let tunnel = mock_server(b"HTTP/1.1 200 OK\r\n\r\nnot a TLS server\r\n");
+        let up = tunneling_proxy(mock_server(b"HTTP/1.1 200 OK\r\n\r\n"));
+
+        let mut connector = ProxyConnector::new(BoxConnector(HttpConnector::new())).unwrap();
+        connector.extend_proxies(vec![tunneling_proxy(tunnel), up]);
+        connector.set_failover(Some(Failover::new()));
+        let result = Runtime::new()
+            .unwrap()
+            .block_on(connector.call("https://example.org/".parse().unwrap()));
+        match result {
+            Err(Error::TargetTls { host, .. }) => assert_eq!(host, "example.org"),
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_without_failover() {
+        let up = tunneling_proxy(mock_server(b"HTTP/1.1 200 OK\r\n\r\n"));
+
+        let mut connector = connector(vec![tunneling_proxy(closed_port()), up], None);
+        let result = Runtime::new()
+            .unwrap()
+            .block_on(connector.call("http://example.org/".parse().unwrap()));
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,312 @@
+//! Health checking of the proxies, with circuit breaking
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use hyper::{rt, Uri};
+use hyper_util::client::legacy::connect::Connection;
+use tower_service::Service;
+
+use crate::{Error, Failover, Proxy, Timeouts, TlsConnect};
+
+/// The configuration of the health checks of the proxies of a
+/// [`ProxyConnector`](crate::ProxyConnector)
+///
+/// Each proxy is tracked by its uri. After a number of consecutive connection failures (see
+/// [`Failover::is_connect_error`]: the TLS handshakes with the targets do not count), its
+/// circuit opens: the proxy is skipped by the connections
+/// matching it, as long as another matching proxy is healthy, and a background task probes it
+/// with a CONNECT request to the canary target until it succeeds. Probing needs a tokio runtime:
+/// without one, circuits never open.
+///
+/// # Example
+/// ```rust
+/// use std::time::Duration;
+/// use hyper_proxy::HealthCheck;
+///
+/// let mut health_check = HealthCheck::new("https://www.example.com".parse().unwrap());
+/// health_check.set_failure_threshold(5);
+/// health_check.set_probe_interval(Duration::from_secs(30));
+/// ```
+#[derive(Debug, Clone)]
+pub struct HealthCheck {
+    canary: Uri,
+    failure_threshold: usize,
+    probe_interval: Duration,
+    probe_timeout: Duration,
+}
+
+impl HealthCheck {
+    /// Create health checks probing unhealthy proxies with a CONNECT request to `canary`
+    ///
+    /// By default, circuits open after 3 consecutive failures, and unhealthy proxies are probed
+    /// every 10 seconds, each probe timing out after 5 seconds.
+    pub fn new(canary: Uri) -> HealthCheck {
+        HealthCheck {
+            canary,
+            failure_threshold: 3,
+            probe_interval: Duration::from_secs(10),
+            probe_timeout: Duration::from_secs(5),
+        }
+    }
+
+    /// Set the number of consecutive failures opening the circuit of a proxy
+    pub fn set_failure_threshold(&mut self, failures: usize) {
+        self.failure_threshold = failures.max(1);
+    }
+
+    /// Set the delay between two probes of an unhealthy proxy
+    pub fn set_probe_interval(&mut self, interval: Duration) {
+        self.probe_interval = interval;
+    }
+
+    /// Set the timeout of a probe
+    pub fn set_probe_timeout(&mut self, timeout: Duration) {
+        self.probe_timeout = timeout;
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    failures: usize,
+    open: bool,
+}
+
+/// The health of the proxies, shared by the clones of a connector
+#[derive(Debug, Clone)]
+pub(crate) struct Health {
+    config: HealthCheck,
+    states: Arc<Mutex<HashMap<Uri, State>>>,
+}
+
+impl Health {
+    pub(crate) fn new(config: HealthCheck) -> Health {
+        Health {
+            config,
+            states: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Returns false while the circuit of the proxy is open
+    pub(crate) fn is_healthy(&self, proxy: &Proxy) -> bool {
+        let states = self.states.lock().unwrap();
+        !states.get(&proxy.uri).is_some_and(|s| s.open)
+    }
+
+    /// Records the result of a connection through `proxy`, starting to probe it if its circuit
+    /// opens
+    pub(crate) fn record<C, T>(
+        &self,
+        proxy: &Proxy,
+        result: &Result<T, Error>,
+        connector: &C,
+        proxy_tls: &Option<Arc<dyn TlsConnect>>,
+    ) where
+        C: Service<Uri> + Clone + Send + 'static,
+        C::Response: Connection + rt::Read + rt::Write + Send + Unpin + 'static,
+        C::Future: Send + 'static,
+        C::Error: Into<Error>,
+    {
+        let failed = match result {
+            Ok(_) => false,
+            Err(e) if Failover::is_connect_error(e) => true,
+            Err(_) => return,
+        };
+
+        let mut states = self.states.lock().unwrap();
+        let state = states.entry(proxy.uri.clone()).or_default();
+        if !failed {
+            *state = State::default();
+            return;
+        }
+        state.failures += 1;
+        if state.open || state.failures < self.config.failure_threshold {
+            return;
+        }
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            state.open = true;
+            let probe = probe(
+                Arc::downgrade(&self.states),
+                self.config.clone(),
+                proxy.clone(),
+                connector.clone(),
+                proxy_tls.clone(),
+            );
+            runtime.spawn(probe);
+        }
+    }
+}
+
+/// Probes an unhealthy proxy until a CONNECT request through it succeeds, closing its circuit
+async fn probe<C>(
+    states: Weak<Mutex<HashMap<Uri, State>>>,
+    config: HealthCheck,
+    mut proxy: Proxy,
+    connector: C,
+    proxy_tls: Option<Arc<dyn TlsConnect>>,
+) where
+    C: Service<Uri> + Clone + Send + 'static,
+    C::Response: Connection + rt::Read + rt::Write + Send + Unpin + 'static,
+    C::Future: Send + 'static,
+    C::Error: Into<Error>,
+{
+    proxy.force_connect = true;
+    loop {
+        tokio::time::sleep(config.probe_interval).await;
+        // the connector was dropped
+        let states = match states.upgrade() {
+            Some(states) => states,
+            None => return,
+        };
+
+        let mut connector = connector.clone();
+        let connection = async {
+            crate::ready(&mut connector).await?;
+            crate::connect(
+                &mut connector,
+                &None,
+                &proxy_tls,
+                &Timeouts::new(),
+                Some(&proxy),
+                config.canary.clone(),
+            )
+            .await
+        };
+        if let Ok(Ok(_)) = tokio::time::timeout(config.probe_timeout, connection).await {
+            states.lock().unwrap().remove(&proxy.uri);
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpListener};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    use hyper_util::client::legacy::connect::HttpConnector;
+    use tokio::runtime::Runtime;
+    use tower_service::Service;
+
+    use super::HealthCheck;
+    #[cfg(any(feature = "tls", feature = "rustls-base", feature = "openssl-tls"))]
+    use crate::Error;
+    use crate::{BoxConnector, Intercept, Proxy, ProxyConnector};
+
+    /// Runs a proxy answering the request of each new connection with the next response, and
+    /// records the requests
+    fn mock_proxy(responses: Vec<&'static str>) -> (SocketAddr, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let recorded = requests.clone();
+        thread::spawn(move || {
+            for response in responses {
+                let (mut sock, _) = listener.accept().unwrap();
+                let mut buf = [0u8; 4096];
+                let n = sock.read(&mut buf).unwrap();
+                let request = String::from_utf8_lossy(&buf[..n]).into_owned();
+                recorded.lock().unwrap().push(request);
+                sock.write_all(response.as_bytes()).unwrap();
+            }
+        });
+        (addr, requests)
+    }
+
+    fn proxy(addr: SocketAddr) -> Proxy {
+        let mut proxy = Proxy::new(Intercept::All, format!("http://{}", addr).parse().unwrap());
+        proxy.force_connect();
+        proxy
+    }
+
+    fn connector(
+        proxies: Vec<Proxy>,
+        health_check: HealthCheck,
+    ) -> ProxyConnector<BoxConnector<HttpConnector>> {
+        let mut connector = ProxyConnector::unsecured(BoxConnector(HttpConnector::new()));
+        connector.extend_proxies(proxies);
+        connector.set_health_check(Some(health_check));
+        connector
+    }
+
+    const BAD_GATEWAY: &str = "HTTP/1.1 502 Bad Gateway\r\n\r\n";
+    const OK: &str = "HTTP/1.1 200 OK\r\n\r\n";
+
+    #[test]
+    fn test_circuit_opens() {
+        let (failing, _) = mock_proxy(vec![BAD_GATEWAY, BAD_GATEWAY]);
+        let (healthy, requests) = mock_proxy(vec![OK]);
+        let mut health_check = HealthCheck::new("http://canary.example".parse().unwrap());
+        health_check.set_failure_threshold(2);
+        health_check.set_probe_interval(Duration::from_secs(3600));
+        let mut connector = connector(vec![proxy(failing), proxy(healthy)], health_check);
+
+        Runtime::new().unwrap().block_on(async {
+            let uri: http::Uri = "http://example.org/".parse().unwrap();
+            connector.call(uri.clone()).await.unwrap_err();
+            assert!(connector.is_healthy(&proxy(failing)));
+            connector.call(uri.clone()).await.unwrap_err();
+            assert!(!connector.is_healthy(&proxy(failing)));
+
+            // the unhealthy proxy is skipped
+            let stream = connector.call(uri).await.unwrap();
+            assert_eq!(stream.proxy(), Some(&proxy(healthy).uri));
+            assert_eq!(requests.lock().unwrap().len(), 1);
+        });
+    }
+
+    #[cfg(any(feature = "tls", feature = "rustls-base", feature = "openssl-tls"))]
+    #[test]
+    fn test_target_tls_failure_not_counted() {
+        // the tunnel is established, but the target does not speak TLS
+        let (addr, _) = mock_proxy(vec!["HTTP/1.1 200 OK\r\n\r\nnot a TLS server\r\n"]);
+        let mut health_check = HealthCheck::new("http://canary.example".parse().unwrap());
+        health_check.set_failure_threshold(1);
+        let mut connector = ProxyConnector::new(BoxConnector(HttpConnector::new())).unwrap();
+        connector.add_proxy(proxy(addr));
+        connector.set_health_check(Some(health_check));
+
+        Runtime::new().unwrap().block_on(async {
+            let result = connector
+                .call("https://example.org/".parse().unwrap())
+                .await;
+            assert!(matches!(result, Err(Error::TargetTls { .. })));
+            assert!(connector.is_healthy(&proxy(addr)));
+        });
+    }
+
+    #[test]
+    fn test_probe_recovers() {
+        let (addr, requests) = mock_proxy(vec![BAD_GATEWAY, BAD_GATEWAY, OK]);
+        let mut health_check = HealthCheck::new("http://canary.example".parse().unwrap());
+        health_check.set_failure_threshold(1);
+        health_check.set_probe_interval(Duration::from_millis(20));
+        let mut connector = connector(vec![proxy(addr)], health_check);
+
+        Runtime::new().unwrap().block_on(async {
+            connector
+                .call("http://example.org/".parse().unwrap())
+                .await
+                .unwrap_err();
+            assert!(!connector.is_healthy(&proxy(addr)));
+
+            for _ in 0..100 {
+                if connector.is_healthy(&proxy(addr)) {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+            assert!(connector.is_healthy(&proxy(addr)));
+        });
+
+        // the first probe failed, and the second succeeded
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 3);
+        assert!(requests[1].starts_with("CONNECT canary.example:80 HTTP/1.1\r\n"));
+        assert!(requests[2].starts_with("CONNECT canary.example:80 HTTP/1.1\r\n"));
+    }
+}
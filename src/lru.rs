@@ -0,0 +1,71 @@
+//! A map bounded in size, evicting the least recently used entries
+
+use std::collections::HashMap;
+use std::hash::Hash;
+
+/// Values evicted least recently used first beyond the capacity
+pub(crate) struct Lru<K, V> {
+    capacity: usize,
+    tick: u64,
+    entries: HashMap<K, (u64, V)>,
+}
+
+impl<K: Eq + Hash + Clone, V: Default> Lru<K, V> {
+    pub(crate) fn new(capacity: usize) -> Lru<K, V> {
+        Lru {
+            capacity,
+            tick: 0,
+            entries: HashMap::new(),
+        }
+    }
+
+    /// Updates the value of `key`, inserting it if needed
+    pub(crate) fn update(&mut self, key: K, f: impl FnOnce(&mut V)) {
+        if self.capacity == 0 {
+            return;
+        }
+        if !self.entries.contains_key(&key) && self.entries.len() == self.capacity {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (used, _))| *used)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.tick += 1;
+        let entry = self.entries.entry(key).or_insert((0, V::default()));
+        entry.0 = self.tick;
+        f(&mut entry.1);
+    }
+
+    /// Reads the value of `key`, if any
+    pub(crate) fn get<T>(&mut self, key: &K, f: impl FnOnce(&mut V) -> Option<T>) -> Option<T> {
+        let entry = self.entries.get_mut(key)?;
+        self.tick += 1;
+        entry.0 = self.tick;
+        f(&mut entry.1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Lru;
+
+    #[test]
+    fn test_lru_evicts_least_recently_used() {
+        let mut lru = Lru::<(u64, String), u32>::new(2);
+        let key = |host: &str| (0, host.to_owned());
+        lru.update(key("a"), |v| *v = 1);
+        lru.update(key("b"), |v| *v = 2);
+        assert_eq!(lru.get(&key("a"), |v| Some(*v)), Some(1));
+        lru.update(key("c"), |v| *v = 3);
+
+        assert_eq!(lru.get(&key("b"), |v| Some(*v)), None);
+        assert_eq!(lru.get(&key("a"), |v| Some(*v)), Some(1));
+        assert_eq!(lru.get(&key("c"), |v| Some(*v)), Some(3));
+        // the scopes are kept apart
+        assert_eq!(lru.get(&(1, "a".to_owned()), |v| Some(*v)), None);
+    }
+}
@@ -0,0 +1,353 @@
+//! NTLMv2 authentication (MS-NLMP)
+//!
+//! The three messages are exchanged on a single connection: the client sends a NEGOTIATE
+//! message (type 1), the proxy answers with a CHALLENGE (type 2), which the client answers with
+//! an AUTHENTICATE message (type 3) proving the knowledge of the password.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use hmac::{Hmac, Mac};
+use md4::{Digest, Md4};
+use md5::Md5;
+
+use crate::auth::ProxyCredentials;
+use crate::Error;
+
+const SIGNATURE: &[u8; 8] = b"NTLMSSP\0";
+
+const NEGOTIATE_UNICODE: u32 = 0x0000_0001;
+const REQUEST_TARGET: u32 = 0x0000_0004;
+const NEGOTIATE_NTLM: u32 = 0x0000_0200;
+const NEGOTIATE_ALWAYS_SIGN: u32 = 0x0000_8000;
+const NEGOTIATE_EXTENDED_SESSIONSECURITY: u32 = 0x0008_0000;
+const NEGOTIATE_TARGET_INFO: u32 = 0x0080_0000;
+const NEGOTIATE_128: u32 = 0x2000_0000;
+const NEGOTIATE_56: u32 = 0x8000_0000;
+
+const FLAGS: u32 = NEGOTIATE_UNICODE
+    | REQUEST_TARGET
+    | NEGOTIATE_NTLM
+    | NEGOTIATE_ALWAYS_SIGN
+    | NEGOTIATE_EXTENDED_SESSIONSECURITY
+    | NEGOTIATE_TARGET_INFO
+    | NEGOTIATE_128
+    | NEGOTIATE_56;
+
+/// The `MsvAvTimestamp` attribute of the target information
+const AV_TIMESTAMP: u16 = 7;
+
+/// Seconds between 1601-01-01 (the Windows epoch) and 1970-01-01
+const WINDOWS_EPOCH_OFFSET: u64 = 11_644_473_600;
+
+/// A client waiting for the CHALLENGE message of the proxy
+#[derive(Debug)]
+pub(crate) struct Negotiation {
+    credentials: ProxyCredentials,
+}
+
+impl Negotiation {
+    /// Starts a negotiation, returning the base64 encoded NEGOTIATE message to send
+    pub(crate) fn start(credentials: ProxyCredentials) -> (Negotiation, String) {
+        let mut message = Vec::with_capacity(32);
+        message.extend_from_slice(SIGNATURE);
+        message.extend_from_slice(&1u32.to_le_bytes());
+        message.extend_from_slice(&FLAGS.to_le_bytes());
+        // empty domain and workstation
+        message.extend_from_slice(&[0; 16]);
+        (Negotiation { credentials }, STANDARD.encode(message))
+    }
+
+    /// Answers the base64 encoded CHALLENGE message, returning the AUTHENTICATE message to send
+    pub(crate) fn authenticate(self, challenge: &str) -> Result<String, Error> {
+        let challenge = Challenge::parse(&STANDARD.decode(challenge.trim()).map_err(invalid)?)?;
+        let mut client_challenge = [0; 8];
+        getrandom::getrandom(&mut client_challenge)
+            .map_err(|e| Error::Io(std::io::Error::other(e.to_string())))?;
+        let timestamp = challenge.timestamp().unwrap_or_else(now);
+        Ok(STANDARD.encode(authenticate_message(
+            &self.credentials,
+            &challenge,
+            client_challenge,
+            timestamp,
+        )))
+    }
+}
+
+fn invalid<E: std::fmt::Display>(e: E) -> Error {
+    Error::InvalidNtlmChallenge(e.to_string())
+}
+
+/// The parts of a CHALLENGE message used to answer it
+#[derive(Debug)]
+struct Challenge {
+    flags: u32,
+    server_challenge: [u8; 8],
+    target_info: Vec<u8>,
+}
+
+impl Challenge {
+    fn parse(message: &[u8]) -> Result<Challenge, Error> {
+        if message.len() < 32 || &message[..8] != SIGNATURE || u32_at(message, 8) != 2 {
+            return Err(invalid("not a CHALLENGE message"));
+        }
+        let flags = u32_at(message, 20);
+        let mut server_challenge = [0; 8];
+        server_challenge.copy_from_slice(&message[24..32]);
+        let target_info = if flags & NEGOTIATE_TARGET_INFO != 0 && message.len() >= 48 {
+            let len = u16::from_le_bytes([message[40], message[41]]) as usize;
+            let offset = u32_at(message, 44) as usize;
+            message
+                .get(offset..offset + len)
+                .ok_or_else(|| invalid("target information out of bounds"))?
+                .to_vec()
+        } else {
+            Vec::new()
+        };
+        Ok(Challenge {
+            flags,
+            server_challenge,
+            target_info,
+        })
+    }
+
+    /// The server time, from the `MsvAvTimestamp` attribute
+    fn timestamp(&self) -> Option<u64> {
+        let mut info = &self.target_info[..];
+        while info.len() >= 4 {
+            let id = u16::from_le_bytes([info[0], info[1]]);
+            let len = u16::from_le_bytes([info[2], info[3]]) as usize;
+            let value = info.get(4..4 + len)?;
+            match id {
+                0 => return None,
+                AV_TIMESTAMP if len == 8 => {
+                    let mut timestamp = [0; 8];
+                    timestamp.copy_from_slice(value);
+                    return Some(u64::from_le_bytes(timestamp));
+                }
+                _ => info = &info[4 + len..],
+            }
+        }
+        None
+    }
+}
+
+fn u32_at(message: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&message[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+/// The current time, in tenths of microseconds since 1601-01-01
+fn now() -> u64 {
+    let since_unix = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    (since_unix.as_secs() + WINDOWS_EPOCH_OFFSET) * 10_000_000
+        + u64::from(since_unix.subsec_nanos() / 100)
+}
+
+fn utf16le(s: &str) -> Vec<u8> {
+    s.encode_utf16().flat_map(u16::to_le_bytes).collect()
+}
+
+fn hmac_md5(key: &[u8], parts: &[&[u8]]) -> [u8; 16] {
+    let mut mac = <Hmac<Md5> as Mac>::new_from_slice(key).expect("HMAC accepts any key size");
+    for part in parts {
+        mac.update(part);
+    }
+    mac.finalize().into_bytes().into()
+}
+
+/// Splits a `DOMAIN\user` username
+fn domain_user(username: &str) -> (&str, &str) {
+    match username.split_once('\\') {
+        Some((domain, user)) => (domain, user),
+        None => ("", username),
+    }
+}
+
+/// The NTOWFv2 key, derived from the password, user and domain
+fn ntowf_v2(credentials: &ProxyCredentials) -> [u8; 16] {
+    let (domain, user) = domain_user(credentials.username());
+    let nt_hash = Md4::digest(utf16le(credentials.password()));
+    hmac_md5(
+        &nt_hash,
+        &[&utf16le(&user.to_uppercase()), &utf16le(domain)],
+    )
+}
+
+/// Computes the NTLMv2 response to the challenge: the proof followed by the client blob
+fn nt_response(
+    key: &[u8; 16],
+    challenge: &Challenge,
+    client_challenge: [u8; 8],
+    timestamp: u64,
+) -> Vec<u8> {
+    let mut blob = vec![1, 1, 0, 0, 0, 0, 0, 0];
+    blob.extend_from_slice(&timestamp.to_le_bytes());
+    blob.extend_from_slice(&client_challenge);
+    blob.extend_from_slice(&[0; 4]);
+    blob.extend_from_slice(&challenge.target_info);
+    blob.extend_from_slice(&[0; 4]);
+
+    let proof = hmac_md5(key, &[&challenge.server_challenge, &blob]);
+    let mut response = proof.to_vec();
+    response.extend_from_slice(&blob);
+    response
+}
+
+fn authenticate_message(
+    credentials: &ProxyCredentials,
+    challenge: &Challenge,
+    client_challenge: [u8; 8],
+    timestamp: u64,
+) -> Vec<u8> {
+    let key = ntowf_v2(credentials);
+    let nt = nt_response(&key, challenge, client_challenge, timestamp);
+    let mut lm = hmac_md5(&key, &[&challenge.server_challenge, &client_challenge]).to_vec();
+    lm.extend_from_slice(&client_challenge);
+
+    let (domain, user) = domain_user(credentials.username());
+    let payloads = [
+        lm,
+        nt,
+        utf16le(domain),
+        utf16le(user),
+        // no workstation, no session key
+        Vec::new(),
+        Vec::new(),
+    ];
+
+    let header_len = 64;
+    let mut message = Vec::with_capacity(header_len + payloads.iter().map(Vec::len).sum::<usize>());
+    message.extend_from_slice(SIGNATURE);
+    message.extend_from_slice(&3u32.to_le_bytes());
+    let mut offset = header_len;
+    for payload in &payloads {
+        let len = payload.len() as u16;
+        message.extend_from_slice(&len.to_le_bytes());
+        message.extend_from_slice(&len.to_le_bytes());
+        message.extend_from_slice(&(offset as u32).to_le_bytes());
+        offset += payload.len();
+    }
+    message.extend_from_slice(&((FLAGS & challenge.flags) | NEGOTIATE_UNICODE).to_le_bytes());
+    for payload in &payloads {
+        message.extend_from_slice(payload);
+    }
+    message
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// The target information of the MS-NLMP 4.2.4 examples
+    const TARGET_INFO: &[u8] = &[
+        0x02, 0x00, 0x0c, 0x00, 0x44, 0x00, 0x6f, 0x00, 0x6d, 0x00, 0x61, 0x00, 0x69, 0x00, 0x6e,
+        0x00, 0x01, 0x00, 0x0c, 0x00, 0x53, 0x00, 0x65, 0x00, 0x72, 0x00, 0x76, 0x00, 0x65, 0x00,
+        0x72, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ];
+
+    /// Builds a CHALLENGE message
+    pub(crate) fn challenge_message(server_challenge: [u8; 8], target_info: &[u8]) -> Vec<u8> {
+        let mut message = SIGNATURE.to_vec();
+        message.extend_from_slice(&2u32.to_le_bytes());
+        // empty target name
+        message.extend_from_slice(&[0, 0, 0, 0, 48, 0, 0, 0]);
+        message.extend_from_slice(&FLAGS.to_le_bytes());
+        message.extend_from_slice(&server_challenge);
+        message.extend_from_slice(&[0; 8]);
+        let len = target_info.len() as u16;
+        message.extend_from_slice(&len.to_le_bytes());
+        message.extend_from_slice(&len.to_le_bytes());
+        message.extend_from_slice(&48u32.to_le_bytes());
+        message.extend_from_slice(target_info);
+        message
+    }
+
+    /// Checks an AUTHENTICATE message, returning the domain and user it authenticates
+    pub(crate) fn verify(
+        message: &[u8],
+        password: &str,
+        server_challenge: [u8; 8],
+    ) -> Option<(String, String)> {
+        let field = |index: usize| {
+            let at = 12 + index * 8;
+            let len = u16::from_le_bytes([message[at], message[at + 1]]) as usize;
+            let offset = u32_at(message, at + 4) as usize;
+            &message[offset..offset + len]
+        };
+        let string = |bytes: &[u8]| {
+            let units: Vec<u16> = bytes
+                .chunks(2)
+                .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                .collect();
+            String::from_utf16_lossy(&units)
+        };
+        if &message[..8] != SIGNATURE || u32_at(message, 8) != 3 {
+            return None;
+        }
+
+        let (domain, user) = (string(field(2)), string(field(3)));
+        let username = if domain.is_empty() {
+            user.clone()
+        } else {
+            format!("{}\\{}", domain, user)
+        };
+        let key = ntowf_v2(&ProxyCredentials::new(username, password));
+        let nt = field(1);
+        let proof = hmac_md5(&key, &[&server_challenge, &nt[16..]]);
+        (proof[..] == nt[..16]).then_some((domain, user))
+    }
+
+    #[test]
+    fn test_ms_nlmp_ntlmv2() {
+        // example of MS-NLMP section 4.2.4
+        let credentials = ProxyCredentials::new("Domain\\User", "Password");
+        assert_eq!(
+            ntowf_v2(&credentials),
+            [
+                0x0c, 0x86, 0x8a, 0x40, 0x3b, 0xfd, 0x7a, 0x93, 0xa3, 0x00, 0x1e, 0xf2, 0x2e, 0xf0,
+                0x2e, 0x3f
+            ]
+        );
+
+        let challenge = Challenge::parse(&challenge_message(
+            [0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef],
+            TARGET_INFO,
+        ))
+        .unwrap();
+        assert_eq!(challenge.target_info, TARGET_INFO);
+        assert_eq!(challenge.timestamp(), None);
+
+        let nt = nt_response(&ntowf_v2(&credentials), &challenge, [0xaa; 8], 0);
+        assert_eq!(
+            nt[..16],
+            [
+                0x68, 0xcd, 0x0a, 0xb8, 0x51, 0xe5, 0x1c, 0x96, 0xaa, 0xbc, 0x92, 0x7b, 0xeb, 0xef,
+                0x6a, 0x1c
+            ]
+        );
+
+        let message = authenticate_message(&credentials, &challenge, [0xaa; 8], 0);
+        assert_eq!(
+            verify(&message, "Password", challenge.server_challenge),
+            Some(("Domain".into(), "User".into()))
+        );
+        assert_eq!(
+            verify(&message, "password", challenge.server_challenge),
+            None
+        );
+    }
+
+    #[test]
+    fn test_invalid_challenge() {
+        let (negotiation, negotiate) = Negotiation::start(ProxyCredentials::new("u", "p"));
+        assert_eq!(&STANDARD.decode(negotiate).unwrap()[..8], SIGNATURE);
+        assert!(matches!(
+            negotiation.authenticate("bm90IG50bG0="),
+            Err(Error::InvalidNtlmChallenge(_))
+        ));
+    }
+}
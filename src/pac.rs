@@ -0,0 +1,614 @@
+//! Proxy auto-config (PAC) scripts
+//!
+//! The script is evaluated by an embedded javascript engine running on a dedicated thread, and
+//! its `FindProxyForURL` results are cached per host.
+
+use std::fmt;
+use std::net::{IpAddr, ToSocketAddrs, UdpSocket};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use boa_engine::{js_string, Context, JsResult, JsString, JsValue, NativeFunction, Source};
+use http::Uri;
+use tokio::sync::{mpsc as async_mpsc, oneshot};
+
+use crate::lru::Lru;
+use crate::Error;
+
+/// Maximum number of hosts whose results are cached, the least recently used being evicted
+const CACHE_CAPACITY: usize = 1024;
+
+/// Maximum duration of a `dnsResolve` lookup, so that a slow resolver cannot stall the engine
+/// evaluating the scripts of every connection
+const DNS_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Maximum number of loop iterations a single script call may run, so that a broken script
+/// cannot hang the connector
+const LOOP_ITERATION_LIMIT: u64 = 100_000;
+
+/// The standard PAC helpers which are not implemented natively
+const PRELUDE: &str = r#"
+function isPlainHostName(host) {
+    return host.indexOf('.') < 0;
+}
+
+function dnsDomainIs(host, domain) {
+    return host.length >= domain.length &&
+        host.substring(host.length - domain.length) == domain;
+}
+
+function localHostOrDomainIs(host, hostdom) {
+    return host == hostdom || hostdom.lastIndexOf(host + '.', 0) == 0;
+}
+
+function isResolvable(host) {
+    return dnsResolve(host) != null;
+}
+
+function convert_addr(ipchars) {
+    var bytes = ipchars.split('.');
+    return ((bytes[0] & 0xff) << 24) | ((bytes[1] & 0xff) << 16) |
+        ((bytes[2] & 0xff) << 8) | (bytes[3] & 0xff);
+}
+
+function isInNet(host, pattern, mask) {
+    var ip = /^\d+\.\d+\.\d+\.\d+$/.test(host) ? host : dnsResolve(host);
+    if (ip == null) {
+        return false;
+    }
+    var m = convert_addr(mask);
+    return (convert_addr(ip) & m) == (convert_addr(pattern) & m);
+}
+
+function dnsDomainLevels(host) {
+    return host.split('.').length - 1;
+}
+
+function shExpMatch(str, shexp) {
+    var re = shexp.replace(/[.+^${}()|[\]\\\/]/g, '\\$&')
+        .replace(/\*/g, '.*')
+        .replace(/\?/g, '.');
+    return new RegExp('^' + re + '$').test(str);
+}
+
+function __pacArgs(args) {
+    var a = Array.prototype.slice.call(args);
+    var gmt = a.length > 0 && a[a.length - 1] == 'GMT';
+    if (gmt) {
+        a.pop();
+    }
+    return { args: a, now: new Date(), gmt: gmt };
+}
+
+var __pacDays = ['SUN', 'MON', 'TUE', 'WED', 'THU', 'FRI', 'SAT'];
+var __pacMonths = ['JAN', 'FEB', 'MAR', 'APR', 'MAY', 'JUN',
+    'JUL', 'AUG', 'SEP', 'OCT', 'NOV', 'DEC'];
+
+function __pacInRange(value, start, end) {
+    return start <= end ? value >= start && value <= end : value >= start || value <= end;
+}
+
+function weekdayRange() {
+    var p = __pacArgs(arguments);
+    var day = p.gmt ? p.now.getUTCDay() : p.now.getDay();
+    var start = __pacDays.indexOf(p.args[0]);
+    var end = p.args.length > 1 ? __pacDays.indexOf(p.args[1]) : start;
+    if (start < 0 || end < 0) {
+        return false;
+    }
+    return __pacInRange(day, start, end);
+}
+
+function dateRange() {
+    var p = __pacArgs(arguments);
+    var now = p.now;
+    var date = {
+        day: p.gmt ? now.getUTCDate() : now.getDate(),
+        month: p.gmt ? now.getUTCMonth() : now.getMonth(),
+        year: p.gmt ? now.getUTCFullYear() : now.getFullYear()
+    };
+    // each argument is a day (number <= 31), a month (name) or a year (number > 31)
+    var fields = p.args.map(function (arg) {
+        var month = __pacMonths.indexOf(arg);
+        if (month >= 0) {
+            return { month: month };
+        }
+        var n = parseInt(arg, 10);
+        return n > 31 ? { year: n } : { day: n };
+    });
+    var bounds = fields.length > 1
+        ? [fields.slice(0, fields.length / 2), fields.slice(fields.length / 2)]
+        : [fields, fields];
+    var key = function (b) {
+        var k = 0;
+        b.forEach(function (f) {
+            if (f.year !== undefined) { k += f.year * 10000; }
+            if (f.month !== undefined) { k += f.month * 100; }
+            if (f.day !== undefined) { k += f.day; }
+        });
+        return k;
+    };
+    var current = function (b) {
+        var k = 0;
+        b.forEach(function (f) {
+            if (f.year !== undefined) { k += date.year * 10000; }
+            if (f.month !== undefined) { k += date.month * 100; }
+            if (f.day !== undefined) { k += date.day; }
+        });
+        return k;
+    };
+    return __pacInRange(current(bounds[0]), key(bounds[0]), key(bounds[1]));
+}
+
+function timeRange() {
+    var p = __pacArgs(arguments);
+    var now = p.now;
+    var a = p.args.map(function (arg) { return parseInt(arg, 10); });
+    var h = p.gmt ? now.getUTCHours() : now.getHours();
+    var m = p.gmt ? now.getUTCMinutes() : now.getMinutes();
+    var s = p.gmt ? now.getUTCSeconds() : now.getSeconds();
+    switch (a.length) {
+        case 1: return h == a[0];
+        case 2: return __pacInRange(h, a[0], a[1] - 1);
+        case 4: return __pacInRange(h * 60 + m, a[0] * 60 + a[1], a[2] * 60 + a[3] - 1);
+        case 6: return __pacInRange(h * 3600 + m * 60 + s,
+            a[0] * 3600 + a[1] * 60 + a[2], a[3] * 3600 + a[4] * 60 + a[5]);
+        default: return false;
+    }
+}
+"#;
+
+/// A proxy returned by a PAC script
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PacProxy {
+    /// Connect directly, without any proxy (`DIRECT`)
+    Direct,
+    /// Connect through a proxy (`PROXY`, `HTTP`, `HTTPS`, `SOCKS`, `SOCKS4` or `SOCKS5`)
+    ///
+    /// The uri scheme is `http`, `https`, `socks4` or `socks5h` respectively.
+    Proxy(Uri),
+}
+
+impl PacProxy {
+    /// Parses a `FindProxyForURL` result, e.g. `PROXY a:8080; SOCKS b:1080; DIRECT`
+    ///
+    /// Unknown proxy types are ignored, an empty result means `DIRECT`.
+    pub fn parse_list(s: &str) -> Result<Vec<PacProxy>, Error> {
+        let mut proxies = Vec::new();
+        for entry in s.split(';').map(str::trim).filter(|e| !e.is_empty()) {
+            let mut parts = entry.split_whitespace();
+            let kind = parts.next().unwrap_or_default().to_ascii_uppercase();
+            let scheme = match &*kind {
+                "DIRECT" => {
+                    proxies.push(PacProxy::Direct);
+                    continue;
+                }
+                "PROXY" | "HTTP" => "http",
+                "HTTPS" => "https",
+                "SOCKS" | "SOCKS4" => "socks4",
+                "SOCKS5" => "socks5h",
+                _ => continue,
+            };
+            let authority = parts
+                .next()
+                .ok_or_else(|| Error::Pac(format!("missing proxy address in '{}'", entry)))?;
+            let uri = format!("{}://{}", scheme, authority)
+                .parse()
+                .map_err(|_| Error::Pac(format!("invalid proxy address in '{}'", entry)))?;
+            proxies.push(PacProxy::Proxy(uri));
+        }
+        if proxies.is_empty() {
+            if s.trim().is_empty() {
+                proxies.push(PacProxy::Direct);
+            } else {
+                return Err(Error::Pac(format!("no supported proxy in '{}'", s)));
+            }
+        }
+        Ok(proxies)
+    }
+}
+
+struct Job {
+    url: String,
+    host: String,
+    reply: oneshot::Sender<Result<String, Error>>,
+}
+
+/// A proxy auto-config script
+///
+/// Cloning a `Pac` is cheap: all clones share the same script engine and cache.
+///
+/// # Example
+/// ```rust,no_run
+/// use hyper_proxy::{Pac, ProxyConnector};
+/// use hyper_util::client::legacy::connect::HttpConnector;
+///
+/// let pac = Pac::new(r#"
+///     function FindProxyForURL(url, host) {
+///         if (isPlainHostName(host) || dnsDomainIs(host, ".internal.example.com")) {
+///             return "DIRECT";
+///         }
+///         return "PROXY proxy.example.com:8080; SOCKS fallback.example.com:1080; DIRECT";
+///     }
+/// "#).unwrap();
+///
+/// let mut connector = ProxyConnector::unsecured(HttpConnector::new());
+/// connector.set_pac(Some(pac));
+/// ```
+#[derive(Clone)]
+pub struct Pac {
+    jobs: async_mpsc::UnboundedSender<Job>,
+    cache: Arc<Mutex<Lru<String, Vec<PacProxy>>>>,
+}
+
+impl fmt::Debug for Pac {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Pac").finish_non_exhaustive()
+    }
+}
+
+impl Pac {
+    /// Compiles a PAC script, which must define a `FindProxyForURL(url, host)` function
+    pub fn new<S: Into<String>>(script: S) -> Result<Pac, Error> {
+        let script = script.into();
+        let (jobs, mut rx) = async_mpsc::unbounded_channel::<Job>();
+        let (init_tx, init_rx) = mpsc::channel();
+
+        thread::Builder::new()
+            .name("hyper-proxy-pac".into())
+            .spawn(move || {
+                let mut context = match engine(&script) {
+                    Ok(context) => {
+                        let _ = init_tx.send(Ok(()));
+                        context
+                    }
+                    Err(e) => {
+                        let _ = init_tx.send(Err(e));
+                        return;
+                    }
+                };
+                // stops once every `Pac` clone is dropped
+                while let Some(job) = rx.blocking_recv() {
+                    let _ = job
+                        .reply
+                        .send(find_proxy_for_url(&mut context, &job.url, &job.host));
+                }
+            })
+            .map_err(Error::Io)?;
+
+        init_rx
+            .recv()
+            .map_err(|_| Error::Pac("PAC engine stopped".into()))??;
+
+        Ok(Pac {
+            jobs,
+            cache: Arc::new(Mutex::new(Lru::new(CACHE_CAPACITY))),
+        })
+    }
+
+    /// Returns the ordered list of proxies the script chooses for `uri`
+    ///
+    /// Results are cached per scheme and host, the rest of the uri is not considered once a
+    /// host has been evaluated.
+    pub async fn find_proxy(&self, uri: &Uri) -> Result<Vec<PacProxy>, Error> {
+        let host = match uri.host() {
+            Some(host) => host.trim_start_matches('[').trim_end_matches(']'),
+            None => return Ok(vec![PacProxy::Direct]),
+        };
+        let key = format!("{}://{}", uri.scheme_str().unwrap_or_default(), host);
+        if let Some(proxies) = self.cache.lock().unwrap().get(&key, |p| Some(p.clone())) {
+            return Ok(proxies);
+        }
+
+        let (reply, rx) = oneshot::channel();
+        let job = Job {
+            url: uri.to_string(),
+            host: host.to_string(),
+            reply,
+        };
+        self.jobs
+            .send(job)
+            .map_err(|_| Error::Pac("PAC engine stopped".into()))?;
+        let result = rx
+            .await
+            .map_err(|_| Error::Pac("PAC engine stopped".into()))??;
+        let proxies = PacProxy::parse_list(&result)?;
+
+        let mut cache = self.cache.lock().unwrap();
+        cache.update(key, |p| *p = proxies.clone());
+        Ok(proxies)
+    }
+}
+
+fn engine(script: &str) -> Result<Context, Error> {
+    let mut context = Context::default();
+    context
+        .runtime_limits_mut()
+        .set_loop_iteration_limit(LOOP_ITERATION_LIMIT);
+
+    let natives: [(JsString, usize, NativeFunction); 3] = [
+        (
+            js_string!("dnsResolve"),
+            1,
+            NativeFunction::from_fn_ptr(dns_resolve),
+        ),
+        (
+            js_string!("myIpAddress"),
+            0,
+            NativeFunction::from_fn_ptr(my_ip_address),
+        ),
+        (js_string!("alert"), 1, NativeFunction::from_fn_ptr(alert)),
+    ];
+    for (name, length, f) in natives {
+        context
+            .register_global_callable(name, length, f)
+            .map_err(js_error)?;
+    }
+
+    context
+        .eval(Source::from_bytes(PRELUDE))
+        .map_err(js_error)?;
+    context.eval(Source::from_bytes(script)).map_err(js_error)?;
+    let f = context
+        .global_object()
+        .get(js_string!("FindProxyForURL"), &mut context)
+        .map_err(js_error)?;
+    if !f.is_callable() {
+        return Err(Error::Pac("script does not define FindProxyForURL".into()));
+    }
+    Ok(context)
+}
+
+fn find_proxy_for_url(context: &mut Context, url: &str, host: &str) -> Result<String, Error> {
+    let f = context
+        .global_object()
+        .get(js_string!("FindProxyForURL"), context)
+        .map_err(js_error)?;
+    let f = f
+        .as_callable()
+        .ok_or_else(|| Error::Pac("script does not define FindProxyForURL".into()))?;
+    let args = [
+        JsValue::from(JsString::from(url)),
+        JsValue::from(JsString::from(host)),
+    ];
+    let result = f
+        .call(&JsValue::undefined(), &args, context)
+        .map_err(js_error)?;
+    if result.is_null_or_undefined() {
+        return Ok(String::new());
+    }
+    Ok(result
+        .to_string(context)
+        .map_err(js_error)?
+        .to_std_string_escaped())
+}
+
+fn js_error(e: boa_engine::JsError) -> Error {
+    Error::Pac(e.to_string())
+}
+
+fn string_arg(args: &[JsValue], context: &mut Context) -> JsResult<String> {
+    Ok(args
+        .first()
+        .cloned()
+        .unwrap_or_default()
+        .to_string(context)?
+        .to_std_string_escaped())
+}
+
+fn dns_resolve(_: &JsValue, args: &[JsValue], context: &mut Context) -> JsResult<JsValue> {
+    let host = string_arg(args, context)?;
+    Ok(match resolve(host, DNS_TIMEOUT) {
+        Some(ip) => JsValue::from(JsString::from(ip.to_string())),
+        None => JsValue::null(),
+    })
+}
+
+/// Resolves the first IPv4 address of `host` on another thread, giving up after `timeout`
+///
+/// A lookup outliving its timeout keeps running on its thread until the resolver answers.
+fn resolve(host: String, timeout: Duration) -> Option<IpAddr> {
+    let (tx, rx) = mpsc::channel();
+    thread::Builder::new()
+        .name("hyper-proxy-pac-dns".into())
+        .spawn(move || {
+            let ip = (&*host, 0)
+                .to_socket_addrs()
+                .ok()
+                .and_then(|mut addrs| addrs.find(|a| a.is_ipv4()))
+                .map(|a| a.ip());
+            let _ = tx.send(ip);
+        })
+        .ok()?;
+    rx.recv_timeout(timeout).ok().flatten()
+}
+
+fn my_ip_address(_: &JsValue, _: &[JsValue], _: &mut Context) -> JsResult<JsValue> {
+    // connecting a udp socket sends nothing but selects the outgoing interface
+    let ip = UdpSocket::bind("0.0.0.0:0")
+        .and_then(|s| s.connect("198.51.100.1:80").map(|_| s))
+        .and_then(|s| s.local_addr())
+        .map(|a| a.ip())
+        .unwrap_or(IpAddr::from([127, 0, 0, 1]));
+    Ok(JsValue::from(JsString::from(ip.to_string())))
+}
+
+fn alert(_: &JsValue, _: &[JsValue], _: &mut Context) -> JsResult<JsValue> {
+    Ok(JsValue::undefined())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BoxConnector, ProxyConnector};
+    use hyper_util::client::legacy::connect::HttpConnector;
+    use std::io::Read;
+    use std::net::TcpListener;
+    use tokio::runtime::Runtime;
+    use tower_service::Service;
+
+    const SCRIPT: &str = r#"
+        var calls = 0;
+        function FindProxyForURL(url, host) {
+            calls += 1;
+            if (host == "calls") {
+                return "PROXY calls:" + calls;
+            }
+            if (isPlainHostName(host) || dnsDomainIs(host, ".internal.example.com")) {
+                return "DIRECT";
+            }
+            if (isInNet(host, "10.0.0.0", "255.0.0.0")) {
+                return "SOCKS5 socks.example.com:1080";
+            }
+            if (shExpMatch(url, "https://*.example.org/*")) {
+                return "HTTPS secure.example.com:443";
+            }
+            if (!weekdayRange("SUN", "SAT") || !timeRange(0, 24)) {
+                return "PROXY never:1";
+            }
+            return "PROXY a.example.com:8080; SOCKS b.example.com:1080; DIRECT";
+        }
+    "#;
+
+    fn find(pac: &Pac, uri: &str) -> Vec<PacProxy> {
+        Runtime::new()
+            .unwrap()
+            .block_on(pac.find_proxy(&uri.parse().unwrap()))
+            .unwrap()
+    }
+
+    fn proxy(uri: &str) -> PacProxy {
+        PacProxy::Proxy(uri.parse().unwrap())
+    }
+
+    #[test]
+    fn test_parse_list() {
+        assert_eq!(
+            PacProxy::parse_list("PROXY a:8080; SOCKS b:1080;DIRECT").unwrap(),
+            vec![
+                proxy("http://a:8080"),
+                proxy("socks4://b:1080"),
+                PacProxy::Direct
+            ]
+        );
+        assert_eq!(
+            PacProxy::parse_list("https a:443; QUIC q:443; socks5 [::1]:1080").unwrap(),
+            vec![proxy("https://a:443"), proxy("socks5h://[::1]:1080")]
+        );
+        assert_eq!(PacProxy::parse_list("").unwrap(), vec![PacProxy::Direct]);
+        assert!(PacProxy::parse_list("QUIC q:443").is_err());
+        assert!(PacProxy::parse_list("PROXY").is_err());
+    }
+
+    #[test]
+    fn test_find_proxy() {
+        let pac = Pac::new(SCRIPT).unwrap();
+        assert_eq!(find(&pac, "http://intranet/"), vec![PacProxy::Direct]);
+        assert_eq!(
+            find(&pac, "http://wiki.internal.example.com/"),
+            vec![PacProxy::Direct]
+        );
+        assert_eq!(
+            find(&pac, "http://10.1.2.3/"),
+            vec![proxy("socks5h://socks.example.com:1080")]
+        );
+        assert_eq!(
+            find(&pac, "https://www.example.org/"),
+            vec![proxy("https://secure.example.com:443")]
+        );
+        assert_eq!(
+            find(&pac, "http://www.example.org/"),
+            vec![
+                proxy("http://a.example.com:8080"),
+                proxy("socks4://b.example.com:1080"),
+                PacProxy::Direct
+            ]
+        );
+    }
+
+    #[test]
+    fn test_cache_per_host() {
+        let pac = Pac::new(SCRIPT).unwrap();
+        let first = find(&pac, "http://calls/a");
+        assert_eq!(find(&pac, "http://calls/b"), first);
+        assert_eq!(find(&pac, "http://calls:8080/c"), first);
+        assert_ne!(find(&pac.clone(), "https://calls/"), first);
+    }
+
+    #[test]
+    fn test_resolve() {
+        let localhost = IpAddr::from([127, 0, 0, 1]);
+        assert_eq!(resolve("127.0.0.1".into(), DNS_TIMEOUT), Some(localhost));
+        assert_eq!(resolve("invalid host".into(), DNS_TIMEOUT), None);
+    }
+
+    #[test]
+    fn test_connector_uses_pac() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let proxy = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0; 1];
+            stream.read(&mut buf).unwrap()
+        });
+
+        let pac = Pac::new(format!(
+            "function FindProxyForURL(url, host) {{ return 'PROXY {}; DIRECT'; }}",
+            addr
+        ))
+        .unwrap();
+        let mut connector = ProxyConnector::unsecured(BoxConnector(HttpConnector::new()));
+        connector.set_pac(Some(pac));
+
+        let uri = "http://example.com/".parse().unwrap();
+        let stream = Runtime::new()
+            .unwrap()
+            .block_on(connector.call(uri))
+            .unwrap();
+        drop(stream);
+        // the connection went to the proxy and was closed without data
+        assert_eq!(proxy.join().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_connector_headers_not_shared() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let pac = Pac::new(format!(
+            "function FindProxyForURL(url, host) {{ return 'PROXY {}'; }}",
+            addr
+        ))
+        .unwrap();
+        let mut configured = crate::Proxy::new(
+            crate::Intercept::All,
+            "http://proxy.example.com:3128".parse().unwrap(),
+        );
+        configured.set_credentials(crate::ProxyCredentials::new("john", "secret"));
+        let mut connector = ProxyConnector::unsecured(BoxConnector(HttpConnector::new()));
+        connector.add_proxy(configured);
+        connector.set_pac(Some(pac));
+
+        // the requests carry the credentials of the configured proxy, not chosen by the script
+        let uri = "http://example.com/".parse().unwrap();
+        let err = Runtime::new()
+            .unwrap()
+            .block_on(connector.call(uri))
+            .unwrap_err();
+        assert!(matches!(err, Error::ProxyHeadersNotShared(_)));
+    }
+
+    #[test]
+    fn test_invalid_script() {
+        assert!(Pac::new("function FindProxyForURL(url, host) {").is_err());
+        assert!(Pac::new("function findProxy(url, host) {}").is_err());
+
+        let pac = Pac::new("function FindProxyForURL(url, host) { while (true) {} }").unwrap();
+        let uri = "http://example.com/".parse().unwrap();
+        let err = Runtime::new()
+            .unwrap()
+            .block_on(pac.find_proxy(&uri))
+            .unwrap_err();
+        assert!(matches!(err, Error::Pac(_)));
+    }
+}
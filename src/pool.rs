@@ -0,0 +1,334 @@
+//! Load balancing across the proxies matching a connection
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use hyper::Uri;
+
+use crate::Proxy;
+
+/// The weight of the latest latency in its moving average
+const EWMA_ALPHA: f64 = 0.3;
+
+/// How a [`ProxyPool`] chooses among the proxies matching a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Strategy {
+    /// Each proxy in turn
+    RoundRobin,
+    /// A random proxy, with a probability proportional to its weight (see
+    /// [`Proxy::set_weight`])
+    WeightedRandom,
+    /// The proxy with the fewest connections being established or still open
+    LeastOutstanding,
+    /// The proxy with the lowest exponentially weighted moving average of its handshake
+    /// latency, the proxies not measured yet being tried first
+    EwmaLatency,
+}
+
+#[derive(Debug, Default)]
+struct Stats {
+    outstanding: usize,
+    latency: Option<f64>,
+}
+
+/// Spreads the connections across all the proxies of a
+/// [`ProxyConnector`](crate::ProxyConnector) matching them
+///
+/// The other matching proxies are kept in order as fallbacks for
+/// [`Failover`](crate::Failover). The statistics of the proxies are shared by the clones of the
+/// pool.
+///
+/// Plain http requests forwarded to a proxy carry the headers of the first matching proxy (see
+/// [`ProxyConnector::http_headers`](crate::ProxyConnector::http_headers)), so the proxies of a
+/// pool forwarding plain http should share their headers: the ones that do not are skipped for
+/// plain http.
+///
+/// # Example
+/// ```rust
+/// use hyper_proxy::{ProxyPool, Strategy};
+///
+/// let pool = ProxyPool::new(Strategy::LeastOutstanding);
+/// ```
+#[derive(Debug, Clone)]
+pub struct ProxyPool {
+    strategy: Strategy,
+    next: Arc<AtomicUsize>,
+    stats: Arc<Mutex<HashMap<Uri, Stats>>>,
+}
+
+impl ProxyPool {
+    /// Create a pool choosing the proxies with `strategy`
+    pub fn new(strategy: Strategy) -> ProxyPool {
+        ProxyPool {
+            strategy,
+            next: Arc::new(AtomicUsize::new(0)),
+            stats: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Get the strategy of the pool
+    pub fn strategy(&self) -> Strategy {
+        self.strategy
+    }
+
+    /// Get the number of connections through the proxy being established or still open
+    pub fn outstanding(&self, proxy: &Proxy) -> usize {
+        let stats = self.stats.lock().unwrap();
+        stats.get(&proxy.uri).map_or(0, |s| s.outstanding)
+    }
+
+    /// Get the moving average of the handshake latency of the proxy, if measured
+    pub fn latency(&self, proxy: &Proxy) -> Option<Duration> {
+        let stats = self.stats.lock().unwrap();
+        let latency = stats.get(&proxy.uri)?.latency?;
+        Some(Duration::from_secs_f64(latency))
+    }
+
+    /// Orders the matching proxies, the first one to be tried first
+    pub(crate) fn order(&self, proxies: &mut [&Proxy]) {
+        if proxies.is_empty() {
+            return;
+        }
+        // rotating first spreads the connections across the proxies on a par
+        let next = self.next.fetch_add(1, Ordering::Relaxed);
+        proxies.rotate_left(next % proxies.len());
+
+        match self.strategy {
+            Strategy::RoundRobin => {}
+            Strategy::WeightedRandom => shuffle_weighted(proxies),
+            Strategy::LeastOutstanding => {
+                let stats = self.stats.lock().unwrap();
+                proxies.sort_by_key(|p| stats.get(&p.uri).map_or(0, |s| s.outstanding));
+            }
+            Strategy::EwmaLatency => {
+                let stats = self.stats.lock().unwrap();
+                let latency = |p: &Proxy| stats.get(&p.uri).and_then(|s| s.latency);
+                proxies.sort_by(|a, b| match (latency(a), latency(b)) {
+                    (Some(a), Some(b)) => a.total_cmp(&b),
+                    (a, b) => a.is_some().cmp(&b.is_some()),
+                });
+            }
+        }
+    }
+
+    /// Counts a connection through the proxy as outstanding until the returned guard is dropped
+    pub(crate) fn start(&self, proxy: &Proxy) -> Outstanding {
+        let mut stats = self.stats.lock().unwrap();
+        stats.entry(proxy.uri.clone()).or_default().outstanding += 1;
+        Outstanding {
+            stats: self.stats.clone(),
+            uri: proxy.uri.clone(),
+        }
+    }
+
+    /// Records the latency of a successful handshake with the proxy
+    pub(crate) fn record_latency(&self, proxy: &Proxy, latency: Duration) {
+        let mut stats = self.stats.lock().unwrap();
+        let stats = stats.entry(proxy.uri.clone()).or_default();
+        let latency = latency.as_secs_f64();
+        stats.latency = Some(match stats.latency {
+            Some(average) => EWMA_ALPHA * latency + (1.0 - EWMA_ALPHA) * average,
+            None => latency,
+        });
+    }
+}
+
+/// A connection through a proxy of a pool, being established or still open
+#[derive(Debug)]
+pub(crate) struct Outstanding {
+    stats: Arc<Mutex<HashMap<Uri, Stats>>>,
+    uri: Uri,
+}
+
+impl Drop for Outstanding {
+    fn drop(&mut self) {
+        if let Some(stats) = self.stats.lock().unwrap().get_mut(&self.uri) {
+            stats.outstanding = stats.outstanding.saturating_sub(1);
+        }
+    }
+}
+
+/// Orders the proxies by a random draw without replacement, weighted by their weights
+fn shuffle_weighted(proxies: &mut [&Proxy]) {
+    for i in 0..proxies.len() {
+        let total: u64 = proxies[i..].iter().map(|p| u64::from(p.weight)).sum();
+        if total == 0 {
+            return;
+        }
+        let mut draw = random() % total;
+        let chosen = proxies[i..]
+            .iter()
+            .position(|p| {
+                let weight = u64::from(p.weight);
+                if draw < weight {
+                    return true;
+                }
+                draw -= weight;
+                false
+            })
+            .unwrap_or(0);
+        proxies.swap(i, i + chosen);
+    }
+}
+
+fn random() -> u64 {
+    let mut bytes = [0; 8];
+    // falls back to the first proxies if the system has no randomness
+    let _ = getrandom::getrandom(&mut bytes);
+    u64::from_le_bytes(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpListener};
+    use std::thread;
+
+    use hyper_util::client::legacy::connect::HttpConnector;
+    use tokio::runtime::Runtime;
+    use tower_service::Service;
+
+    use super::{ProxyPool, Strategy};
+    use crate::{BoxConnector, Intercept, Proxy, ProxyConnector, ProxyCredentials, ProxyMode};
+
+    /// Runs a proxy accepting every CONNECT request, and keeping the tunnels open until the
+    /// client closes them
+    fn mock_proxy() -> Proxy {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for sock in listener.incoming() {
+                let mut sock = sock.unwrap();
+                thread::spawn(move || {
+                    let mut buf = [0u8; 4096];
+                    let _ = sock.read(&mut buf).unwrap();
+                    sock.write_all(b"HTTP/1.1 200 OK\r\n\r\n").unwrap();
+                    while sock.read(&mut buf).is_ok_and(|n| n > 0) {}
+                });
+            }
+        });
+        proxy(addr)
+    }
+
+    fn proxy(addr: SocketAddr) -> Proxy {
+        let mut proxy = Proxy::new(Intercept::All, format!("http://{}", addr).parse().unwrap());
+        proxy.force_connect();
+        proxy
+    }
+
+    fn connector(
+        proxies: &[Proxy],
+        strategy: Strategy,
+    ) -> ProxyConnector<BoxConnector<HttpConnector>> {
+        let mut connector = ProxyConnector::unsecured(BoxConnector(HttpConnector::new()));
+        connector.extend_proxies(proxies.iter().cloned());
+        connector.set_pool(Some(ProxyPool::new(strategy)));
+        connector
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let proxies = [mock_proxy(), mock_proxy()];
+        let mut connector = connector(&proxies, Strategy::RoundRobin);
+
+        Runtime::new().unwrap().block_on(async {
+            let mut used = Vec::new();
+            for _ in 0..4 {
+                let stream = connector.call("http://example.org/".parse().unwrap()).await;
+                used.push(stream.unwrap().proxy().cloned().unwrap());
+            }
+            assert_ne!(used[0], used[1]);
+            assert_eq!(used[0], used[2]);
+            assert_eq!(used[1], used[3]);
+        });
+    }
+
+    #[test]
+    fn test_plain_http_headers_not_shared() {
+        let proxies: Vec<Proxy> = ["john", "jane"]
+            .iter()
+            .map(|user| {
+                let mut proxy = mock_proxy();
+                proxy.force_connect = false;
+                proxy.set_credentials(ProxyCredentials::new(*user, "secret"));
+                proxy
+            })
+            .collect();
+        let mut connector = connector(&proxies, Strategy::RoundRobin);
+
+        Runtime::new().unwrap().block_on(async {
+            // the requests carrying the credentials of john are only forwarded to its proxy
+            let uri: http::Uri = "http://example.org/".parse().unwrap();
+            for _ in 0..2 {
+                let stream = connector.call(uri.clone()).await.unwrap();
+                assert_eq!(stream.info().mode(), ProxyMode::Forward);
+                assert_eq!(stream.proxy(), Some(proxies[0].uri()));
+            }
+            // tunneled connections use the whole pool
+            let uri: http::Uri = "https://example.org/".parse().unwrap();
+            let first = connector.call(uri.clone()).await.unwrap();
+            let second = connector.call(uri).await.unwrap();
+            assert_ne!(first.proxy(), second.proxy());
+        });
+    }
+
+    #[test]
+    fn test_least_outstanding() {
+        let proxies = [mock_proxy(), mock_proxy()];
+        let mut connector = connector(&proxies, Strategy::LeastOutstanding);
+
+        Runtime::new().unwrap().block_on(async {
+            let uri: http::Uri = "http://example.org/".parse().unwrap();
+            let first = connector.call(uri.clone()).await.unwrap();
+            let second = connector.call(uri.clone()).await.unwrap();
+            // the proxy of the open connection is avoided
+            assert_ne!(first.proxy(), second.proxy());
+            let pool = connector.pool().unwrap().clone();
+            assert_eq!(pool.outstanding(&proxies[0]), 1);
+            assert_eq!(pool.outstanding(&proxies[1]), 1);
+
+            drop(first);
+            let third = connector.call(uri).await.unwrap();
+            assert_eq!(pool.outstanding(&proxies[0]), 1);
+            assert_eq!(pool.outstanding(&proxies[1]), 1);
+            drop((second, third));
+            assert_eq!(pool.outstanding(&proxies[0]), 0);
+            assert_eq!(pool.outstanding(&proxies[1]), 0);
+        });
+    }
+
+    #[test]
+    fn test_weighted_random() {
+        let mut unused = mock_proxy();
+        unused.set_weight(0);
+        let proxies = [unused, mock_proxy()];
+        let mut connector = connector(&proxies, Strategy::WeightedRandom);
+
+        Runtime::new().unwrap().block_on(async {
+            for _ in 0..5 {
+                let stream = connector.call("http://example.org/".parse().unwrap()).await;
+                assert_eq!(stream.unwrap().proxy(), Some(&proxies[1].uri));
+            }
+        });
+    }
+
+    #[test]
+    fn test_ewma_latency() {
+        let proxies = [mock_proxy(), mock_proxy()];
+        let mut connector = connector(&proxies, Strategy::EwmaLatency);
+
+        Runtime::new().unwrap().block_on(async {
+            let uri: http::Uri = "http://example.org/".parse().unwrap();
+            let first = connector.call(uri.clone()).await.unwrap();
+            // the proxy not measured yet is tried
+            let second = connector.call(uri).await.unwrap();
+            assert_ne!(first.proxy(), second.proxy());
+
+            let pool = connector.pool().unwrap();
+            assert!(pool.latency(&proxies[0]).is_some());
+            assert!(pool.latency(&proxies[1]).is_some());
+        });
+    }
+}
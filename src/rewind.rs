@@ -0,0 +1,102 @@
+//! A stream replaying the bytes read past the end of a proxy response
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use bytes::{Buf, Bytes};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// A stream tunneled through a proxy, replaying the bytes the proxy sent right after its
+/// response to the handshake before reading from the underlying stream
+///
+/// A proxy may send the first bytes of the tunneled stream along with its response to the
+/// CONNECT request: they are read with the response, and kept here to be read first.
+#[derive(Debug)]
+pub struct Rewind<T> {
+    buffered: Option<Bytes>,
+    inner: T,
+}
+
+impl<T> Rewind<T> {
+    pub(crate) fn new(inner: T) -> Rewind<T> {
+        Rewind {
+            buffered: None,
+            inner,
+        }
+    }
+
+    pub(crate) fn new_buffered(inner: T, buffered: Bytes) -> Rewind<T> {
+        Rewind {
+            buffered: Some(buffered).filter(|b| !b.is_empty()),
+            inner,
+        }
+    }
+
+    /// Get the bytes read past the response of the proxy, and not read from this stream yet
+    pub fn buffered(&self) -> &[u8] {
+        self.buffered.as_deref().unwrap_or_default()
+    }
+
+    /// Get the underlying stream
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    /// Takes up to `len` buffered bytes
+    fn take(&mut self, len: usize) -> Option<Bytes> {
+        let buffered = self.buffered.as_mut()?;
+        let taken = buffered.split_to(len.min(buffered.len()));
+        if !buffered.has_remaining() {
+            self.buffered = None;
+        }
+        Some(taken)
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Rewind<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match this.take(buf.remaining()) {
+            Some(buffered) => {
+                buf.put_slice(&buffered);
+                Poll::Ready(Ok(()))
+            }
+            None => Pin::new(&mut this.inner).poll_read(cx, buf),
+        }
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Rewind<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
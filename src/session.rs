@@ -0,0 +1,375 @@
+//! TLS session resumption shared across the connections to the targets
+
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+#[cfg(any(feature = "rustls-base", feature = "openssl-tls"))]
+use std::sync::Mutex;
+
+#[cfg(any(feature = "rustls-base", feature = "openssl-tls"))]
+use crate::lru::Lru;
+#[cfg(feature = "openssl-tls")]
+use crate::Error;
+
+/// A cache of the TLS sessions of the targets, resuming them across the connections
+///
+/// The handshake of a connection to a target offers the session of a previous connection to
+/// this target, saving a round trip through the proxy when the target accepts it. The sessions
+/// are keyed by host, and the cache holds the ones of at most `capacity` hosts, evicting the
+/// least recently used. They are shared by the clones of the cache, and by the clones of the
+/// [`ProxyConnector`](crate::ProxyConnector) using it, but not across connectors built with
+/// different options.
+///
+/// Sessions are resumed by the `rustls-base` and `openssl-tls` backends: native-tls, the default
+/// backend with the `tls` feature, does not expose its sessions, so its handshakes are never
+/// resumed nor counted. The cache counts the handshakes it offered a session to, and the ones the
+/// target resumed: it may decline the session offered, e.g. after restarting.
+///
+/// Rebuilding the tls of a connector, with
+/// [`ProxyConnector::set_alpn_protocols`](crate::ProxyConnector::set_alpn_protocols),
+/// [`ProxyConnector::set_client_identity`](crate::ProxyConnector::set_client_identity),
+/// [`ProxyConnector::add_client_identity`](crate::ProxyConnector::add_client_identity) or
+/// [`ProxyConnector::set_tls_options`](crate::ProxyConnector::set_tls_options), starts over with
+/// no session: the sessions cached before may have been negotiated with another client
+/// certificate or ALPN protocol. These are best set before the first connection.
+///
+/// # Example
+/// ```rust
+/// # #[cfg(feature = "openssl-tls")]
+/// # fn run() -> Result<(), hyper_proxy::Error> {
+/// use hyper_proxy::{ProxyConnector, SessionCache, TlsBackend, TlsOptions};
+/// use hyper_util::client::legacy::connect::HttpConnector;
+///
+/// let cache = SessionCache::new(1024);
+/// let mut options = TlsOptions::new();
+/// options.set_backend(Some(TlsBackend::OpenSsl));
+/// options.set_session_cache(Some(cache.clone()));
+/// let connector = ProxyConnector::with_tls_options(HttpConnector::new(), options)?;
+///
+/// // later
+/// println!("{:.0}% of the handshakes resumed a session", cache.resumption_rate() * 100.);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct SessionCache {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    capacity: usize,
+    scopes: AtomicU64,
+    handshakes: AtomicU64,
+    offered: AtomicU64,
+    resumed: AtomicU64,
+    #[cfg(feature = "rustls-base")]
+    rustls: Mutex<Lru<(u64, String), rustls::Sessions>>,
+    #[cfg(feature = "openssl-tls")]
+    openssl: Mutex<Lru<(u64, String), Option<openssl::ssl::SslSession>>>,
+}
+
+impl fmt::Debug for SessionCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SessionCache")
+            .field("capacity", &self.inner.capacity)
+            .field("handshakes", &self.handshakes())
+            .field("offered", &self.offered())
+            .field("resumed", &self.resumed())
+            .finish_non_exhaustive()
+    }
+}
+
+impl SessionCache {
+    /// Create a cache holding the sessions of at most `capacity` hosts
+    pub fn new(capacity: usize) -> SessionCache {
+        SessionCache {
+            inner: Arc::new(Inner {
+                capacity,
+                scopes: AtomicU64::new(0),
+                handshakes: AtomicU64::new(0),
+                offered: AtomicU64::new(0),
+                resumed: AtomicU64::new(0),
+                #[cfg(feature = "rustls-base")]
+                rustls: Mutex::new(Lru::new(capacity)),
+                #[cfg(feature = "openssl-tls")]
+                openssl: Mutex::new(Lru::new(capacity)),
+            }),
+        }
+    }
+
+    /// Get the number of handshakes which looked up a session
+    pub fn handshakes(&self) -> u64 {
+        self.inner.handshakes.load(Ordering::Relaxed)
+    }
+
+    /// Get the number of handshakes which found a session to offer to the target
+    pub fn offered(&self) -> u64 {
+        self.inner.offered.load(Ordering::Relaxed)
+    }
+
+    /// Get the share of the handshakes which found a session to offer to the target, 0 before
+    /// the first handshake
+    pub fn offer_rate(&self) -> f64 {
+        match self.handshakes() {
+            0 => 0.,
+            handshakes => self.offered() as f64 / handshakes as f64,
+        }
+    }
+
+    /// Get the number of handshakes which resumed the session offered to the target
+    pub fn resumed(&self) -> u64 {
+        self.inner.resumed.load(Ordering::Relaxed)
+    }
+
+    /// Get the share of the handshakes which resumed a session, 0 before the first handshake
+    pub fn resumption_rate(&self) -> f64 {
+        match self.handshakes() {
+            0 => 0.,
+            handshakes => self.resumed() as f64 / handshakes as f64,
+        }
+    }
+
+    /// Counts a handshake resuming a session
+    pub(crate) fn count_resumed(&self) {
+        self.inner.resumed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns a new scope, keeping apart the sessions of the connectors built with different
+    /// options
+    pub(crate) fn scope(&self) -> u64 {
+        self.inner.scopes.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Counts a handshake, and a session offered if it found one
+    #[cfg(any(feature = "rustls-base", feature = "openssl-tls"))]
+    fn count<T>(&self, session: Option<T>) -> Option<T> {
+        self.inner.handshakes.fetch_add(1, Ordering::Relaxed);
+        if session.is_some() {
+            self.inner.offered.fetch_add(1, Ordering::Relaxed);
+        }
+        session
+    }
+
+    /// Stores the sessions of the rustls connections of `scope`
+    #[cfg(feature = "rustls-base")]
+    pub(crate) fn rustls_store(
+        &self,
+        scope: u64,
+    ) -> Arc<dyn tokio_rustls::rustls::client::ClientSessionStore> {
+        Arc::new(rustls::Store {
+            cache: self.clone(),
+            scope,
+        })
+    }
+
+    /// Stores the sessions of the openssl connections of `scope`, resumed by
+    /// [`SessionCache::resume_openssl`]
+    #[cfg(feature = "openssl-tls")]
+    pub(crate) fn configure_openssl(
+        &self,
+        builder: &mut openssl::ssl::SslConnectorBuilder,
+        scope: u64,
+    ) -> Result<(), Error> {
+        use openssl::ssl::{SslSession, SslSessionCacheMode};
+
+        let host_index = openssl_indexes()?.1;
+        builder.set_session_cache_mode(
+            SslSessionCacheMode::CLIENT | SslSessionCacheMode::NO_INTERNAL_STORE,
+        );
+        let cache = self.clone();
+        builder.set_new_session_callback(move |ssl, session| {
+            // openssl marks the session of a connection dropped without a shutdown as not
+            // resumable, so a copy is kept
+            let session = session.to_der().and_then(|der| SslSession::from_der(&der));
+            if let (Some(host), Ok(session)) = (ssl.ex_data(host_index), session) {
+                let mut sessions = cache.inner.openssl.lock().unwrap();
+                sessions.update((scope, host.clone()), |s| *s = Some(session));
+            }
+        });
+        builder.set_ex_data(openssl_indexes()?.0, (self.clone(), scope));
+        Ok(())
+    }
+
+    /// Offers the cached session of `host` to the target, if the connector of `ssl` was
+    /// configured by [`SessionCache::configure_openssl`]
+    #[cfg(feature = "openssl-tls")]
+    pub(crate) fn resume_openssl(ssl: &mut openssl::ssl::SslRef, host: &str) -> Result<(), Error> {
+        use openssl::ssl::SslVersion;
+
+        let (context_index, host_index) = openssl_indexes()?;
+        let (cache, scope) = match ssl.ssl_context().ex_data(context_index) {
+            Some((cache, scope)) => (cache.clone(), *scope),
+            None => return Ok(()),
+        };
+        ssl.set_ex_data(host_index, host.to_owned());
+
+        let session = cache
+            .inner
+            .openssl
+            .lock()
+            .unwrap()
+            .get(&(scope, host.to_owned()), |s| {
+                let session = s.clone()?;
+                // TLS 1.3 tickets are only used once
+                if session.protocol_version() == SslVersion::TLS1_3 {
+                    *s = None;
+                }
+                Some(session)
+            });
+        if let Some(session) = cache.count(session) {
+            // the session was created by the same context
+            unsafe { ssl.set_session(&session)? };
+        }
+        Ok(())
+    }
+}
+
+/// The indexes of the session cache in the openssl contexts, and of the host in the connections
+#[cfg(feature = "openssl-tls")]
+#[allow(clippy::type_complexity)]
+fn openssl_indexes() -> Result<
+    (
+        openssl::ex_data::Index<openssl::ssl::SslContext, (SessionCache, u64)>,
+        openssl::ex_data::Index<openssl::ssl::Ssl, String>,
+    ),
+    Error,
+> {
+    use openssl::ssl::{Ssl, SslContext};
+    use std::sync::OnceLock;
+
+    static INDEXES: OnceLock<(
+        openssl::ex_data::Index<SslContext, (SessionCache, u64)>,
+        openssl::ex_data::Index<Ssl, String>,
+    )> = OnceLock::new();
+
+    if let Some(indexes) = INDEXES.get() {
+        return Ok(*indexes);
+    }
+    let indexes = (SslContext::new_ex_index()?, Ssl::new_ex_index()?);
+    Ok(*INDEXES.get_or_init(|| indexes))
+}
+
+#[cfg(feature = "rustls-base")]
+mod rustls {
+    use std::collections::VecDeque;
+
+    use tokio_rustls::rustls::client::{
+        ClientSessionStore, Tls12ClientSessionValue, Tls13ClientSessionValue,
+    };
+    use tokio_rustls::rustls::pki_types::ServerName;
+    use tokio_rustls::rustls::NamedGroup;
+
+    use super::SessionCache;
+
+    /// The TLS 1.3 tickets kept per host
+    const MAX_TICKETS: usize = 8;
+
+    #[derive(Default)]
+    pub(super) struct Sessions {
+        kx_hint: Option<NamedGroup>,
+        tls12: Option<Tls12ClientSessionValue>,
+        tls13: VecDeque<Tls13ClientSessionValue>,
+    }
+
+    #[derive(Debug)]
+    pub(super) struct Store {
+        pub(super) cache: SessionCache,
+        pub(super) scope: u64,
+    }
+
+    impl Store {
+        fn key(&self, server_name: &ServerName<'_>) -> (u64, String) {
+            (self.scope, server_name.to_str().into_owned())
+        }
+
+        fn update(&self, server_name: &ServerName<'_>, f: impl FnOnce(&mut Sessions)) {
+            let mut sessions = self.cache.inner.rustls.lock().unwrap();
+            sessions.update(self.key(server_name), f);
+        }
+
+        fn get<T>(
+            &self,
+            server_name: &ServerName<'_>,
+            f: impl FnOnce(&mut Sessions) -> Option<T>,
+        ) -> Option<T> {
+            let mut sessions = self.cache.inner.rustls.lock().unwrap();
+            sessions.get(&self.key(server_name), f)
+        }
+    }
+
+    impl ClientSessionStore for Store {
+        fn set_kx_hint(&self, server_name: ServerName<'static>, group: NamedGroup) {
+            self.update(&server_name, |s| s.kx_hint = Some(group));
+        }
+
+        fn kx_hint(&self, server_name: &ServerName<'_>) -> Option<NamedGroup> {
+            self.get(server_name, |s| s.kx_hint)
+        }
+
+        fn set_tls12_session(
+            &self,
+            server_name: ServerName<'static>,
+            value: Tls12ClientSessionValue,
+        ) {
+            self.update(&server_name, |s| s.tls12 = Some(value));
+        }
+
+        fn tls12_session(&self, server_name: &ServerName<'_>) -> Option<Tls12ClientSessionValue> {
+            // only looked up when there is no TLS 1.3 ticket, the handshake is already counted
+            let session = self.get(server_name, |s| s.tls12.clone());
+            if session.is_some() {
+                self.cache
+                    .inner
+                    .offered
+                    .fetch_add(1, super::Ordering::Relaxed);
+            }
+            session
+        }
+
+        fn remove_tls12_session(&self, server_name: &ServerName<'static>) {
+            self.update(server_name, |s| s.tls12 = None);
+        }
+
+        fn insert_tls13_ticket(
+            &self,
+            server_name: ServerName<'static>,
+            value: Tls13ClientSessionValue,
+        ) {
+            self.update(&server_name, |s| {
+                if s.tls13.len() == MAX_TICKETS {
+                    s.tls13.pop_front();
+                }
+                s.tls13.push_back(value);
+            });
+        }
+
+        fn take_tls13_ticket(
+            &self,
+            server_name: &ServerName<'static>,
+        ) -> Option<Tls13ClientSessionValue> {
+            // looked up first by each handshake
+            let ticket = self.get(server_name, |s| s.tls13.pop_back());
+            self.cache.count(ticket)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+
+    use super::SessionCache;
+
+    #[test]
+    fn test_offer_rate() {
+        let cache = SessionCache::new(8);
+        assert_eq!(cache.offer_rate(), 0.);
+        cache.inner.handshakes.store(4, Ordering::Relaxed);
+        cache.inner.offered.store(1, Ordering::Relaxed);
+        assert_eq!(cache.offer_rate(), 0.25);
+        assert_eq!(cache.resumption_rate(), 0.);
+        cache.count_resumed();
+        assert_eq!(cache.resumption_rate(), 0.25);
+        assert_ne!(cache.scope(), cache.scope());
+    }
+}
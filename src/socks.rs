@@ -0,0 +1,492 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+use http::Uri;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::error::Error;
+
+const SOCKS4_VERSION: u8 = 0x04;
+const SOCKS4_CMD_CONNECT: u8 = 0x01;
+
+const SOCKS4_REPLY_VERSION: u8 = 0x00;
+const SOCKS4_GRANTED: u8 = 0x5a;
+const SOCKS4_REJECTED: u8 = 0x5b;
+const SOCKS4_IDENTD_UNREACHABLE: u8 = 0x5c;
+const SOCKS4_IDENTD_MISMATCH: u8 = 0x5d;
+
+const SOCKS5_VERSION: u8 = 0x05;
+
+const SOCKS5_AUTH_NONE: u8 = 0x00;
+const SOCKS5_AUTH_USERNAME_PASSWORD: u8 = 0x02;
+const SOCKS5_AUTH_NO_ACCEPTABLE: u8 = 0xff;
+
+const SOCKS5_USERNAME_PASSWORD_VERSION: u8 = 0x01;
+
+const SOCKS5_CMD_CONNECT: u8 = 0x01;
+
+const SOCKS5_ATYP_IPV4: u8 = 0x01;
+const SOCKS5_ATYP_DOMAIN: u8 = 0x03;
+const SOCKS5_ATYP_IPV6: u8 = 0x04;
+
+/// The default port of a SOCKS proxy
+pub(crate) const DEFAULT_PORT: u16 = 1080;
+
+/// The SOCKS protocol spoken with a proxy, derived from the proxy uri scheme
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum SocksVersion {
+    /// `socks4://`, the target host is resolved locally
+    V4,
+    /// `socks4a://`, the target host is resolved by the proxy
+    V4a,
+    /// `socks5://`, the target host is resolved locally
+    V5,
+    /// `socks5h://`, the target host is resolved by the proxy
+    V5h,
+}
+
+impl SocksVersion {
+    /// Returns the SOCKS version matching the scheme of `proxy_uri`, if any
+    pub fn from_uri(proxy_uri: &Uri) -> Option<SocksVersion> {
+        match proxy_uri.scheme_str()? {
+            s if s.eq_ignore_ascii_case("socks4") => Some(SocksVersion::V4),
+            s if s.eq_ignore_ascii_case("socks4a") => Some(SocksVersion::V4a),
+            s if s.eq_ignore_ascii_case("socks5") => Some(SocksVersion::V5),
+            s if s.eq_ignore_ascii_case("socks5h") => Some(SocksVersion::V5h),
+            _ => None,
+        }
+    }
+
+    fn remote_dns(self) -> bool {
+        match self {
+            SocksVersion::V4 | SocksVersion::V5 => false,
+            SocksVersion::V4a | SocksVersion::V5h => true,
+        }
+    }
+}
+
+pub(crate) struct SocksConnect {
+    version: SocksVersion,
+    target_host: String,
+    port: u16,
+    credentials: Option<(String, String)>,
+}
+
+/// Creates a new SOCKS handshake through proxy
+pub(crate) fn new(
+    version: SocksVersion,
+    target_host: &str,
+    port: u16,
+    credentials: Option<(String, String)>,
+) -> SocksConnect {
+    SocksConnect {
+        version,
+        target_host: target_host.to_owned(),
+        port,
+        credentials,
+    }
+}
+
+/// Target address of the CONNECT request
+enum Address {
+    Ip(IpAddr),
+    Domain(String),
+}
+
+impl SocksConnect {
+    /// Performs the handshake on `stream`, returning it once the proxy is connected to the target
+    pub async fn with_stream<S>(self, mut stream: S) -> Result<S, Error>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let address = self.resolve().await?;
+        match self.version {
+            SocksVersion::V4 | SocksVersion::V4a => {
+                // SOCKS4 has no authentication, only a user id which is the username
+                let user_id = self.credentials.as_ref().map_or("", |(user, _)| user);
+                socks4_connect(&mut stream, address, self.port, user_id).await?;
+            }
+            SocksVersion::V5 | SocksVersion::V5h => {
+                self.socks5_authenticate(&mut stream).await?;
+                socks5_connect(&mut stream, address, self.port).await?;
+            }
+        }
+        Ok(stream)
+    }
+
+    async fn resolve(&self) -> Result<Address, Error> {
+        // ipv6 literals are bracketed in uris
+        let host = self
+            .target_host
+            .trim_start_matches('[')
+            .trim_end_matches(']');
+
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(Address::Ip(ip));
+        }
+
+        if self.version.remote_dns() {
+            return Ok(Address::Domain(host.to_owned()));
+        }
+
+        // SOCKS4 can only address ipv4 targets
+        let ipv4_only = self.version == SocksVersion::V4;
+        tokio::net::lookup_host((host, self.port))
+            .await?
+            .find(|addr: &SocketAddr| !ipv4_only || addr.is_ipv4())
+            .map(|addr| Address::Ip(addr.ip()))
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!("failed to resolve {}", host),
+                )
+                .into()
+            })
+    }
+
+    async fn socks5_authenticate<S>(&self, stream: &mut S) -> Result<(), Error>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let greeting: &[u8] = if self.credentials.is_some() {
+            &[
+                SOCKS5_VERSION,
+                2,
+                SOCKS5_AUTH_NONE,
+                SOCKS5_AUTH_USERNAME_PASSWORD,
+            ]
+        } else {
+            &[SOCKS5_VERSION, 1, SOCKS5_AUTH_NONE]
+        };
+        stream.write_all(greeting).await?;
+
+        let mut reply = [0u8; 2];
+        read_exact(stream, &mut reply).await?;
+        if reply[0] != SOCKS5_VERSION {
+            return Err(Error::InvalidSocksResponse(format!(
+                "unexpected version {:#04x}",
+                reply[0]
+            )));
+        }
+
+        match (reply[1], &self.credentials) {
+            (SOCKS5_AUTH_NONE, _) => Ok(()),
+            (SOCKS5_AUTH_USERNAME_PASSWORD, Some((username, password))) => {
+                // RFC 1929
+                if username.len() > 255 || password.len() > 255 {
+                    return Err(Error::InvalidSocksCredentials);
+                }
+
+                let mut buf = Vec::with_capacity(3 + username.len() + password.len());
+                buf.push(SOCKS5_USERNAME_PASSWORD_VERSION);
+                buf.push(username.len() as u8);
+                buf.extend_from_slice(username.as_bytes());
+                buf.push(password.len() as u8);
+                buf.extend_from_slice(password.as_bytes());
+                stream.write_all(&buf).await?;
+
+                let mut reply = [0u8; 2];
+                read_exact(stream, &mut reply).await?;
+                if reply[1] != 0 {
+                    return Err(Error::SocksAuthenticationFailed);
+                }
+                Ok(())
+            }
+            (SOCKS5_AUTH_NO_ACCEPTABLE, _) => Err(Error::SocksNoAcceptableAuthMethod),
+            (method, _) => Err(Error::InvalidSocksResponse(format!(
+                "unexpected authentication method {:#04x}",
+                method
+            ))),
+        }
+    }
+}
+
+async fn socks4_connect<S>(
+    stream: &mut S,
+    address: Address,
+    port: u16,
+    user_id: &str,
+) -> Result<(), Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut buf = vec![SOCKS4_VERSION, SOCKS4_CMD_CONNECT];
+    buf.extend_from_slice(&port.to_be_bytes());
+    match address {
+        Address::Ip(IpAddr::V4(ip)) => {
+            buf.extend_from_slice(&ip.octets());
+            buf.extend_from_slice(user_id.as_bytes());
+            buf.push(0);
+        }
+        Address::Ip(IpAddr::V6(ip)) => return Err(Error::InvalidSocksTarget(ip.to_string())),
+        Address::Domain(domain) => {
+            // SOCKS4a: an invalid ip 0.0.0.x tells the proxy the domain follows the user id
+            buf.extend_from_slice(&Ipv4Addr::new(0, 0, 0, 1).octets());
+            buf.extend_from_slice(user_id.as_bytes());
+            buf.push(0);
+            buf.extend_from_slice(domain.as_bytes());
+            buf.push(0);
+        }
+    }
+    stream.write_all(&buf).await?;
+
+    let mut reply = [0u8; 8];
+    read_exact(stream, &mut reply).await?;
+    if reply[0] != SOCKS4_REPLY_VERSION {
+        return Err(Error::InvalidSocksResponse(format!(
+            "unexpected reply version {:#04x}",
+            reply[0]
+        )));
+    }
+
+    match reply[1] {
+        SOCKS4_GRANTED => Ok(()),
+        SOCKS4_REJECTED => Err(Error::Socks4RequestRejected),
+        SOCKS4_IDENTD_UNREACHABLE => Err(Error::Socks4IdentdUnreachable),
+        SOCKS4_IDENTD_MISMATCH => Err(Error::Socks4IdentdMismatch),
+        code => Err(Error::InvalidSocksResponse(format!(
+            "unexpected reply code {:#04x}",
+            code
+        ))),
+    }
+}
+
+async fn socks5_connect<S>(stream: &mut S, address: Address, port: u16) -> Result<(), Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut buf = vec![SOCKS5_VERSION, SOCKS5_CMD_CONNECT, 0x00];
+    match address {
+        Address::Ip(IpAddr::V4(ip)) => {
+            buf.push(SOCKS5_ATYP_IPV4);
+            buf.extend_from_slice(&ip.octets());
+        }
+        Address::Ip(IpAddr::V6(ip)) => {
+            buf.push(SOCKS5_ATYP_IPV6);
+            buf.extend_from_slice(&ip.octets());
+        }
+        Address::Domain(domain) => {
+            if domain.len() > 255 {
+                return Err(Error::InvalidSocksTarget(domain));
+            }
+            buf.push(SOCKS5_ATYP_DOMAIN);
+            buf.push(domain.len() as u8);
+            buf.extend_from_slice(domain.as_bytes());
+        }
+    }
+    buf.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&buf).await?;
+
+    let mut reply = [0u8; 4];
+    read_exact(stream, &mut reply).await?;
+    if reply[0] != SOCKS5_VERSION {
+        return Err(Error::InvalidSocksResponse(format!(
+            "unexpected version {:#04x}",
+            reply[0]
+        )));
+    }
+    if reply[1] != 0x00 {
+        return Err(Error::Socks5ConnectFailed {
+            code: reply[1],
+            reason: socks5_reply_reason(reply[1]),
+        });
+    }
+
+    // skip the bound address, it is of no use for a CONNECT
+    let len = match reply[3] {
+        SOCKS5_ATYP_IPV4 => 4,
+        SOCKS5_ATYP_IPV6 => 16,
+        SOCKS5_ATYP_DOMAIN => {
+            let mut len = [0u8; 1];
+            read_exact(stream, &mut len).await?;
+            len[0] as usize
+        }
+        atyp => {
+            return Err(Error::InvalidSocksResponse(format!(
+                "unexpected address type {:#04x}",
+                atyp
+            )))
+        }
+    };
+    let mut bound = vec![0u8; len + 2];
+    read_exact(stream, &mut bound).await?;
+
+    Ok(())
+}
+
+fn socks5_reply_reason(code: u8) -> &'static str {
+    match code {
+        0x01 => "general SOCKS server failure",
+        0x02 => "connection not allowed by ruleset",
+        0x03 => "network unreachable",
+        0x04 => "host unreachable",
+        0x05 => "connection refused",
+        0x06 => "TTL expired",
+        0x07 => "command not supported",
+        0x08 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+async fn read_exact<S: AsyncRead + Unpin>(stream: &mut S, buf: &mut [u8]) -> Result<(), Error> {
+    match stream.read_exact(buf).await {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Err(Error::UnexpectedEOF),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpListener};
+    use std::thread;
+
+    use tokio::net::TcpStream;
+    use tokio::runtime::Runtime;
+
+    use super::SocksVersion;
+    use crate::error::Error;
+
+    fn mock_socks(exchange: Vec<(Vec<u8>, Vec<u8>)>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        thread::spawn(move || {
+            let (mut sock, _) = listener.accept().unwrap();
+            for (expected, reply) in exchange {
+                let mut buf = vec![0u8; expected.len()];
+                sock.read_exact(&mut buf).unwrap();
+                assert_eq!(buf, expected);
+                sock.write_all(&reply).unwrap();
+            }
+        });
+        addr
+    }
+
+    fn handshake(
+        addr: SocketAddr,
+        version: SocksVersion,
+        host: &str,
+        credentials: Option<(String, String)>,
+    ) -> Result<(), Error> {
+        let core = Runtime::new().unwrap();
+        core.block_on(async {
+            let tcp = TcpStream::connect(&addr).await?;
+            super::new(version, host, 443, credentials)
+                .with_stream(tcp)
+                .await
+                .map(drop)
+        })
+    }
+
+    #[test]
+    fn test_socks4_user_id() {
+        let addr = mock_socks(vec![(
+            b"\x04\x01\x01\xbb\x7f\x00\x00\x01John\x00".to_vec(),
+            vec![0, 0x5a, 0, 0, 0, 0, 0, 0],
+        )]);
+
+        let credentials = Some(("John".to_owned(), String::new()));
+        handshake(addr, SocksVersion::V4, "127.0.0.1", credentials).unwrap();
+    }
+
+    #[test]
+    fn test_socks4a_remote_dns() {
+        let addr = mock_socks(vec![(
+            b"\x04\x01\x01\xbb\x00\x00\x00\x01\x00example.com\x00".to_vec(),
+            vec![0, 0x5a, 0, 0, 0, 0, 0, 0],
+        )]);
+
+        handshake(addr, SocksVersion::V4a, "example.com", None).unwrap();
+    }
+
+    #[test]
+    fn test_socks4_reply_codes() {
+        for code in [0x5b, 0x5c, 0x5d] {
+            let addr = mock_socks(vec![(
+                b"\x04\x01\x01\xbb\x7f\x00\x00\x01\x00".to_vec(),
+                vec![0, code, 0, 0, 0, 0, 0, 0],
+            )]);
+
+            match (code, handshake(addr, SocksVersion::V4, "127.0.0.1", None)) {
+                (0x5b, Err(Error::Socks4RequestRejected))
+                | (0x5c, Err(Error::Socks4IdentdUnreachable))
+                | (0x5d, Err(Error::Socks4IdentdMismatch)) => {}
+                (code, res) => panic!("unexpected result {:?} for code {:#04x}", res, code),
+            }
+        }
+    }
+
+    #[test]
+    fn test_socks5h_remote_dns() {
+        let addr = mock_socks(vec![
+            (vec![5, 1, 0], vec![5, 0]),
+            (
+                b"\x05\x01\x00\x03\x0bexample.com\x01\xbb".to_vec(),
+                vec![5, 0, 0, 1, 127, 0, 0, 1, 0, 80],
+            ),
+        ]);
+
+        handshake(addr, SocksVersion::V5h, "example.com", None).unwrap();
+    }
+
+    #[test]
+    fn test_socks5_ip_literal() {
+        let addr = mock_socks(vec![
+            (vec![5, 1, 0], vec![5, 0]),
+            (
+                vec![
+                    5, 1, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 187,
+                ],
+                vec![5, 0, 0, 3, 3, b'f', b'o', b'o', 0, 80],
+            ),
+        ]);
+
+        handshake(addr, SocksVersion::V5, "[::1]", None).unwrap();
+    }
+
+    #[test]
+    fn test_socks5_username_password() {
+        let addr = mock_socks(vec![
+            (vec![5, 2, 0, 2], vec![5, 2]),
+            (b"\x01\x04John\x09Agent1234".to_vec(), vec![1, 0]),
+            (
+                vec![5, 1, 0, 1, 127, 0, 0, 1, 1, 187],
+                vec![5, 0, 0, 1, 127, 0, 0, 1, 0, 80],
+            ),
+        ]);
+
+        let credentials = Some(("John".to_owned(), "Agent1234".to_owned()));
+        handshake(addr, SocksVersion::V5, "127.0.0.1", credentials).unwrap();
+    }
+
+    #[test]
+    fn test_socks5_bad_credentials() {
+        let addr = mock_socks(vec![
+            (vec![5, 2, 0, 2], vec![5, 2]),
+            (b"\x01\x04John\x03Doe".to_vec(), vec![1, 1]),
+        ]);
+
+        let credentials = Some(("John".to_owned(), "Doe".to_owned()));
+        match handshake(addr, SocksVersion::V5, "127.0.0.1", credentials) {
+            Err(Error::SocksAuthenticationFailed) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_socks5_connect_refused() {
+        let addr = mock_socks(vec![
+            (vec![5, 1, 0], vec![5, 0]),
+            (
+                vec![5, 1, 0, 1, 127, 0, 0, 1, 1, 187],
+                vec![5, 5, 0, 1, 0, 0, 0, 0, 0, 0],
+            ),
+        ]);
+
+        match handshake(addr, SocksVersion::V5, "127.0.0.1", None) {
+            Err(Error::Socks5ConnectFailed { code: 5, .. }) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+}
@@ -1,53 +1,316 @@
+use std::fmt;
 use std::io;
 use std::pin::Pin;
 use std::task::{Context, Poll};
+use std::time::Duration;
 
+use http::Uri;
 use hyper::rt;
 use hyper_util::rt::TokioIo;
-#[cfg(feature = "rustls-base")]
-use tokio_rustls::client::TlsStream as RustlsStream;
+use tokio::io::{AsyncRead, AsyncWrite};
 
-#[cfg(all(feature = "tls", feature = "rustls"))]
-compile_error!("cannot combine tls and rustls");
+use hyper_util::client::legacy::connect::{Connected, Connection};
 
-#[cfg(feature = "tls")]
-use tokio_native_tls::TlsStream;
+use crate::pool::Outstanding;
+use crate::rewind::Rewind;
+use crate::{ConnectResponse, TlsStream};
 
-#[cfg(feature = "openssl-tls")]
-use tokio_openssl::SslStream as OpenSslStream;
+/// How a connection goes through a proxy
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyMode {
+    /// A direct connection to the target
+    Direct,
+    /// The requests are forwarded by the proxy
+    Forward,
+    /// A tunnel opened with a CONNECT request
+    Connect,
+    /// A tunnel opened with a SOCKS handshake
+    Socks,
+    /// A tunnel through the hops of a [`ProxyChain`](crate::ProxyChain)
+    Chain,
+}
 
-use hyper_util::client::legacy::connect::{Connected, Connection};
+/// How a connection was established, added to the extensions of the responses received through
+/// it
+///
+/// # Example
+/// ```rust,no_run
+/// # async fn run(res: http::Response<()>) {
+/// use hyper_proxy::{ProxyInfo, ProxyMode};
+///
+/// if let Some(info) = res.extensions().get::<ProxyInfo>() {
+///     if info.mode() != ProxyMode::Direct {
+///         println!("served through {:?} in {:?}", info.uri(), info.handshake_duration());
+///     }
+/// }
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct ProxyInfo {
+    uri: Option<Uri>,
+    mode: ProxyMode,
+    handshake_duration: Duration,
+    proxy_tls: bool,
+    target_tls: bool,
+    alpn_protocol: Option<Vec<u8>>,
+}
 
-#[cfg(feature = "rustls-base")]
-pub type TlsStream<R> = RustlsStream<R>;
+impl ProxyInfo {
+    pub(crate) fn new(uri: Option<Uri>, mode: ProxyMode) -> ProxyInfo {
+        ProxyInfo {
+            uri,
+            mode,
+            handshake_duration: Duration::ZERO,
+            proxy_tls: false,
+            target_tls: false,
+            alpn_protocol: None,
+        }
+    }
+
+    pub(crate) fn direct() -> ProxyInfo {
+        ProxyInfo::new(None, ProxyMode::Direct)
+    }
 
-#[cfg(feature = "openssl-tls")]
-pub type TlsStream<R> = OpenSslStream<R>;
+    pub(crate) fn set_handshake_duration(&mut self, duration: Duration) {
+        self.handshake_duration = duration;
+    }
+
+    pub(crate) fn set_tls(&mut self, proxy_tls: bool, target_tls: bool) {
+        self.proxy_tls = proxy_tls;
+        self.target_tls = target_tls;
+    }
+
+    pub(crate) fn set_alpn_protocol(&mut self, protocol: Option<Vec<u8>>) {
+        self.alpn_protocol = protocol;
+    }
+
+    /// Get the uri of the proxy, without its credentials, or `None` for a direct connection
+    ///
+    /// For a [`ProxyChain`](crate::ProxyChain), this is the first hop of the chain.
+    pub fn uri(&self) -> Option<&Uri> {
+        self.uri.as_ref()
+    }
+
+    /// Get how the connection goes through the proxy
+    pub fn mode(&self) -> ProxyMode {
+        self.mode
+    }
+
+    /// Get the time taken to establish the connection, from the TCP connection to the end of
+    /// the handshakes
+    pub fn handshake_duration(&self) -> Duration {
+        self.handshake_duration
+    }
+
+    /// Returns true if the connection to the proxy was secured with TLS by the connector
+    ///
+    /// For a [`ProxyChain`](crate::ProxyChain), this is true if any hop was.
+    pub fn proxy_tls(&self) -> bool {
+        self.proxy_tls
+    }
+
+    /// Returns true if the connection to the target was secured with TLS by the connector,
+    /// through the tunnel
+    pub fn target_tls(&self) -> bool {
+        self.target_tls
+    }
+
+    /// Get the ALPN protocol selected by the target during the TLS handshake, if any
+    pub fn alpn_protocol(&self) -> Option<&[u8]> {
+        self.alpn_protocol.as_deref()
+    }
+}
 
 /// A Proxy Stream wrapper
-pub enum ProxyStream<R> {
+///
+/// Besides the connection itself, it reports the proxy the connection goes through.
+pub struct ProxyStream<R> {
+    kind: ProxyStreamKind<R>,
+    info: ProxyInfo,
+    // counts the connection as outstanding in its proxy pool until dropped
+    outstanding: Option<Outstanding>,
+    connect_response: Option<ConnectResponse>,
+}
+
+impl<R> ProxyStream<R> {
+    pub(crate) fn new(kind: ProxyStreamKind<R>, info: ProxyInfo) -> ProxyStream<R> {
+        ProxyStream {
+            kind,
+            info,
+            outstanding: None,
+            connect_response: None,
+        }
+    }
+
+    pub(crate) fn set_connect_response(&mut self, response: Option<ConnectResponse>) {
+        self.connect_response = response;
+    }
+
+    pub(crate) fn set_outstanding(&mut self, outstanding: Outstanding) {
+        self.outstanding = Some(outstanding);
+    }
+
+    /// Get the layers of the connection
+    pub fn kind(&self) -> &ProxyStreamKind<R> {
+        &self.kind
+    }
+
+    /// Get the layers of the connection, consuming the stream
+    pub fn into_kind(self) -> ProxyStreamKind<R> {
+        self.kind
+    }
+
+    /// Get the uri of the proxy the connection goes through, without its credentials, or
+    /// `None` for a direct connection
+    ///
+    /// For a [`ProxyChain`](crate::ProxyChain), this is the first hop of the chain.
+    pub fn proxy(&self) -> Option<&Uri> {
+        self.info.uri()
+    }
+
+    /// Get how the connection was established
+    ///
+    /// It is also added to the extensions of the responses received through the connection.
+    pub fn info(&self) -> &ProxyInfo {
+        &self.info
+    }
+
+    /// Get the response of the proxy to the CONNECT request opening the tunnel, if any
+    ///
+    /// It is also added to the extensions of the responses received through the connection.
+    /// For a [`ProxyChain`](crate::ProxyChain), this is the response of the last hop.
+    pub fn connect_response(&self) -> Option<&ConnectResponse> {
+        self.connect_response.as_ref()
+    }
+}
+
+impl<R> fmt::Debug for ProxyStream<R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match &self.kind {
+            ProxyStreamKind::NoProxy(_) => "NoProxy",
+            ProxyStreamKind::Regular(_) => "Regular",
+            ProxyStreamKind::Secured(_) => "Secured",
+            ProxyStreamKind::SecuredProxy(_) => "SecuredProxy",
+            ProxyStreamKind::SecuredOverSecuredProxy(_) => "SecuredOverSecuredProxy",
+            ProxyStreamKind::Chained(_) => "Chained",
+        };
+        f.debug_struct("ProxyStream")
+            .field("kind", &kind)
+            .field("info", &self.info)
+            .finish()
+    }
+}
+
+/// The layers of a [`ProxyStream`]
+pub enum ProxyStreamKind<R> {
     NoProxy(R),
-    Regular(R),
-    #[cfg(any(feature = "tls", feature = "rustls-base", feature = "openssl-tls"))]
-    Secured(Box<TokioIo<TlsStream<TokioIo<R>>>>),
+    /// A connection to the proxy, either forwarding requests or tunneling plain http to the
+    /// target
+    Regular(TokioIo<Rewind<TokioIo<R>>>),
+    /// A TLS connection to the target, tunneled through the proxy
+    Secured(TokioIo<Box<dyn TlsStream>>),
+    /// A TLS connection to an `https://` proxy, either forwarding requests or tunneling plain
+    /// http to the target
+    SecuredProxy(TokioIo<Rewind<Box<dyn TlsStream>>>),
+    /// A TLS connection to the target, tunneled through a TLS connection to an `https://` proxy
+    SecuredOverSecuredProxy(TokioIo<Box<dyn TlsStream>>),
+    /// A connection tunneled through a [`ProxyChain`](crate::ProxyChain)
+    Chained(ChainedStream),
+}
+
+/// A stream tunneled through the hops of a proxy chain, possibly secured with TLS at each hop
+pub(crate) trait ChainIo: AsyncRead + AsyncWrite + Send + Unpin {
+    /// The connection information of the underlying connection to the first hop
+    fn connected(&self) -> Connected;
+}
+
+impl<R: rt::Read + rt::Write + Connection + Send + Unpin> ChainIo for TokioIo<R> {
+    fn connected(&self) -> Connected {
+        self.inner().connected()
+    }
+}
+
+impl<S: ChainIo> ChainIo for Rewind<S> {
+    fn connected(&self) -> Connected {
+        self.inner().connected()
+    }
+}
+
+impl ChainIo for Box<dyn ChainIo> {
+    fn connected(&self) -> Connected {
+        (**self).connected()
+    }
+}
+
+/// The stream of a connection tunneled through a [`ProxyChain`](crate::ProxyChain)
+pub struct ChainedStream(TokioIo<Box<dyn ChainIo>>);
+
+impl ChainedStream {
+    pub(crate) fn new(stream: Box<dyn ChainIo>) -> ChainedStream {
+        ChainedStream(TokioIo::new(stream))
+    }
+}
+
+impl rt::Read for ChainedStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: rt::ReadBufCursor<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().0).poll_read(cx, buf)
+    }
+}
+
+impl rt::Write for ChainedStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().0).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<Result<usize, io::Error>> {
+        Pin::new(&mut self.get_mut().0).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.0.is_write_vectored()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().0).poll_shutdown(cx)
+    }
 }
 
 macro_rules! match_fn_pinned {
     ($self:expr, $fn:ident, $ctx:expr, $buf:expr) => {
-        match $self.get_mut() {
-            ProxyStream::NoProxy(s) => Pin::new(s).$fn($ctx, $buf),
-            ProxyStream::Regular(s) => Pin::new(s).$fn($ctx, $buf),
-            #[cfg(any(feature = "tls", feature = "rustls-base", feature = "openssl-tls"))]
-            ProxyStream::Secured(s) => Pin::new(s).$fn($ctx, $buf),
+        match &mut $self.get_mut().kind {
+            ProxyStreamKind::NoProxy(s) => Pin::new(s).$fn($ctx, $buf),
+            ProxyStreamKind::Regular(s) => Pin::new(s).$fn($ctx, $buf),
+            ProxyStreamKind::Secured(s) => Pin::new(s).$fn($ctx, $buf),
+            ProxyStreamKind::SecuredProxy(s) => Pin::new(s).$fn($ctx, $buf),
+            ProxyStreamKind::SecuredOverSecuredProxy(s) => Pin::new(s).$fn($ctx, $buf),
+            ProxyStreamKind::Chained(s) => Pin::new(s).$fn($ctx, $buf),
         }
     };
 
     ($self:expr, $fn:ident, $ctx:expr) => {
-        match $self.get_mut() {
-            ProxyStream::NoProxy(s) => Pin::new(s).$fn($ctx),
-            ProxyStream::Regular(s) => Pin::new(s).$fn($ctx),
-            #[cfg(any(feature = "tls", feature = "rustls-base", feature = "openssl-tls"))]
-            ProxyStream::Secured(s) => Pin::new(s).$fn($ctx),
+        match &mut $self.get_mut().kind {
+            ProxyStreamKind::NoProxy(s) => Pin::new(s).$fn($ctx),
+            ProxyStreamKind::Regular(s) => Pin::new(s).$fn($ctx),
+            ProxyStreamKind::Secured(s) => Pin::new(s).$fn($ctx),
+            ProxyStreamKind::SecuredProxy(s) => Pin::new(s).$fn($ctx),
+            ProxyStreamKind::SecuredOverSecuredProxy(s) => Pin::new(s).$fn($ctx),
+            ProxyStreamKind::Chained(s) => Pin::new(s).$fn($ctx),
         }
     };
 }
@@ -80,11 +343,13 @@ impl<R: rt::Read + rt::Write + Unpin> rt::Write for ProxyStream<R> {
     }
 
     fn is_write_vectored(&self) -> bool {
-        match self {
-            ProxyStream::NoProxy(s) => s.is_write_vectored(),
-            ProxyStream::Regular(s) => s.is_write_vectored(),
-            #[cfg(any(feature = "tls", feature = "rustls-base", feature = "openssl-tls"))]
-            ProxyStream::Secured(s) => s.is_write_vectored(),
+        match &self.kind {
+            ProxyStreamKind::NoProxy(s) => s.is_write_vectored(),
+            ProxyStreamKind::Regular(s) => s.is_write_vectored(),
+            ProxyStreamKind::Secured(s) => s.is_write_vectored(),
+            ProxyStreamKind::SecuredProxy(s) => s.is_write_vectored(),
+            ProxyStreamKind::SecuredOverSecuredProxy(s) => s.is_write_vectored(),
+            ProxyStreamKind::Chained(s) => s.is_write_vectored(),
         }
     }
 
@@ -99,25 +364,126 @@ impl<R: rt::Read + rt::Write + Unpin> rt::Write for ProxyStream<R> {
 
 impl<R: rt::Read + rt::Write + Connection + Unpin> Connection for ProxyStream<R> {
     fn connected(&self) -> Connected {
-        match self {
-            ProxyStream::NoProxy(s) => s.connected(),
-
-            ProxyStream::Regular(s) => s.connected().proxy(true),
-            #[cfg(feature = "tls")]
-            ProxyStream::Secured(s) => s
-                .inner()
-                .get_ref()
-                .get_ref()
-                .get_ref()
-                .inner()
-                .connected()
-                .proxy(true),
-
-            #[cfg(feature = "rustls-base")]
-            ProxyStream::Secured(s) => s.inner().get_ref().0.inner().connected().proxy(true),
-
-            #[cfg(feature = "openssl-tls")]
-            ProxyStream::Secured(s) => s.inner().get_ref().inner().connected().proxy(true),
+        let connected = match &self.kind {
+            ProxyStreamKind::NoProxy(s) => s.connected(),
+
+            ProxyStreamKind::Regular(s) => s.inner().inner().inner().connected().proxy(true),
+            ProxyStreamKind::Secured(s) => s.inner().connected().proxy(true),
+            ProxyStreamKind::SecuredProxy(s) => s.inner().connected().proxy(true),
+            ProxyStreamKind::SecuredOverSecuredProxy(s) => s.inner().connected().proxy(true),
+            // the requests are sent to the target through the tunnel
+            ProxyStreamKind::Chained(s) => s.0.inner().connected(),
+        };
+        let connected = if self.info.alpn_protocol() == Some(b"h2") {
+            connected.negotiated_h2()
+        } else {
+            connected
+        };
+        let connected = connected.extra(self.info.clone());
+        match &self.connect_response {
+            Some(response) => connected.extra(response.clone()),
+            None => connected,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpListener};
+    use std::thread;
+
+    use http_body_util::Empty;
+    use hyper::body::Bytes;
+    use hyper_util::client::legacy::{connect::HttpConnector, Client};
+    use hyper_util::rt::TokioExecutor;
+    use tokio::runtime::Runtime;
+
+    use tower_service::Service;
+
+    use super::{ProxyInfo, ProxyMode};
+    use crate::{BoxConnector, ConnectResponse, Intercept, Proxy, ProxyConnector};
+
+    /// Runs a proxy accepting a CONNECT request with headers, and answering the request sent
+    /// through the tunnel
+    fn mock_proxy() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut sock, _) = listener.accept().unwrap();
+            let mut buf = [0u8; 4096];
+            let _ = sock.read(&mut buf).unwrap();
+            sock.write_all(b"HTTP/1.1 200 OK\r\nX-Egress-IP: 192.0.2.1\r\n\r\n")
+                .unwrap();
+            let _ = sock.read(&mut buf).unwrap();
+            sock.write_all(b"HTTP/1.1 204 No Content\r\n\r\n").unwrap();
+        });
+        addr
+    }
+
+    /// Accepts a connection, and keeps it open until the client closes it
+    fn mock_server() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut sock, _) = listener.accept().unwrap();
+            let mut buf = [0u8; 4096];
+            while sock.read(&mut buf).is_ok_and(|n| n > 0) {}
+        });
+        addr
+    }
+
+    #[test]
+    fn test_proxy_info() {
+        let proxy_addr = mock_server();
+        let target = mock_server();
+        let uri = format!("http://john:secret@{}", proxy_addr)
+            .parse()
+            .unwrap();
+        // only example.org goes through the proxy
+        let intercept =
+            |_: Option<&str>, host: Option<&str>, _: Option<u16>| host == Some("example.org");
+        let proxy = Proxy::new(intercept, uri);
+        let mut connector = ProxyConnector::unsecured(BoxConnector(HttpConnector::new()));
+        connector.add_proxy(proxy);
+
+        Runtime::new().unwrap().block_on(async {
+            let stream = connector.call("http://example.org/".parse().unwrap()).await;
+            let info = stream.unwrap().info().clone();
+            assert_eq!(info.mode(), ProxyMode::Forward);
+            let proxy_uri = format!("http://{}", proxy_addr)
+                .parse::<http::Uri>()
+                .unwrap();
+            assert_eq!(info.uri(), Some(&proxy_uri));
+            assert!(!info.proxy_tls() && !info.target_tls());
+
+            let stream = connector
+                .call(format!("http://{}/", target).parse().unwrap())
+                .await;
+            let info = stream.unwrap().info().clone();
+            assert_eq!(info.mode(), ProxyMode::Direct);
+            assert!(info.uri().is_none());
+        });
+    }
+
+    #[test]
+    fn test_connect_response_extension() {
+        let addr = mock_proxy();
+        let mut proxy = Proxy::new(Intercept::All, format!("http://{}", addr).parse().unwrap());
+        proxy.force_connect();
+        let mut connector = ProxyConnector::unsecured(BoxConnector(HttpConnector::new()));
+        connector.add_proxy(proxy);
+        let client = Client::builder(TokioExecutor::new()).build::<_, Empty<Bytes>>(connector);
+
+        let res = Runtime::new()
+            .unwrap()
+            .block_on(client.get("http://example.org/".parse().unwrap()))
+            .unwrap();
+        assert_eq!(res.status(), http::StatusCode::NO_CONTENT);
+        let connect = res.extensions().get::<ConnectResponse>().unwrap();
+        assert_eq!(connect.status(), http::StatusCode::OK);
+        assert_eq!(connect.headers()["x-egress-ip"], "192.0.2.1");
+        let info = res.extensions().get::<ProxyInfo>().unwrap();
+        assert_eq!(info.mode(), ProxyMode::Connect);
+    }
+}
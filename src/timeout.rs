@@ -0,0 +1,232 @@
+//! Timeouts of the phases of a connection through a proxy
+
+use std::fmt;
+use std::future::Future;
+use std::time::Duration;
+
+use hyper::Uri;
+
+use crate::Error;
+
+/// A phase of a connection through a proxy, timing out with [`Error::Timeout`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeoutPhase {
+    /// The TCP connection to the proxy
+    Connect,
+    /// The handshake with the proxy: the CONNECT request until its response, including the
+    /// authentication rounds, or the SOCKS handshake
+    Handshake,
+    /// The TLS handshake with an `https://` proxy
+    TlsHandshake,
+    /// The TLS handshake with the target, through the tunnel
+    TargetTlsHandshake,
+}
+
+impl fmt::Display for TimeoutPhase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            TimeoutPhase::Connect => "TCP connect",
+            TimeoutPhase::Handshake => "proxy handshake",
+            TimeoutPhase::TlsHandshake => "TLS handshake",
+            TimeoutPhase::TargetTlsHandshake => "target TLS handshake",
+        })
+    }
+}
+
+/// The timeouts of the phases of a connection through a proxy, none by default
+///
+/// Timeouts are set on a [`Proxy`](crate::Proxy), or on a
+/// [`ProxyConnector`](crate::ProxyConnector) for all its proxies, the timeouts of a proxy
+/// taking precedence. They need a tokio runtime with the time driver enabled.
+///
+/// # Example
+/// ```rust
+/// use std::time::Duration;
+/// use hyper_proxy::Timeouts;
+///
+/// let mut timeouts = Timeouts::new();
+/// timeouts.set_connect(Some(Duration::from_secs(3)));
+/// timeouts.set_handshake(Some(Duration::from_secs(10)));
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Timeouts {
+    connect: Option<Duration>,
+    handshake: Option<Duration>,
+    tls_handshake: Option<Duration>,
+}
+
+impl Timeouts {
+    /// Create timeouts with none set
+    pub fn new() -> Timeouts {
+        Timeouts::default()
+    }
+
+    /// Set the timeout of the TCP connection to the proxy
+    pub fn set_connect(&mut self, timeout: Option<Duration>) {
+        self.connect = timeout;
+    }
+
+    /// Set the timeout of the handshake with the proxy
+    pub fn set_handshake(&mut self, timeout: Option<Duration>) {
+        self.handshake = timeout;
+    }
+
+    /// Set the timeout of each TLS handshake
+    pub fn set_tls_handshake(&mut self, timeout: Option<Duration>) {
+        self.tls_handshake = timeout;
+    }
+
+    /// Get the timeout of a phase
+    pub fn get(&self, phase: TimeoutPhase) -> Option<Duration> {
+        match phase {
+            TimeoutPhase::Connect => self.connect,
+            TimeoutPhase::Handshake => self.handshake,
+            TimeoutPhase::TlsHandshake | TimeoutPhase::TargetTlsHandshake => self.tls_handshake,
+        }
+    }
+
+    /// Returns these timeouts, the unset ones taken from `defaults`
+    pub(crate) fn or(&self, defaults: &Timeouts) -> Timeouts {
+        Timeouts {
+            connect: self.connect.or(defaults.connect),
+            handshake: self.handshake.or(defaults.handshake),
+            tls_handshake: self.tls_handshake.or(defaults.tls_handshake),
+        }
+    }
+
+    /// Runs the `phase` of a connection through `proxy_uri`, failing with [`Error::Timeout`]
+    /// once its timeout elapsed
+    pub(crate) async fn run<F, T>(
+        &self,
+        phase: TimeoutPhase,
+        proxy_uri: &Uri,
+        future: F,
+    ) -> Result<T, Error>
+    where
+        F: Future<Output = Result<T, Error>>,
+    {
+        match self.get(phase) {
+            Some(timeout) => match tokio::time::timeout(timeout, future).await {
+                Ok(result) => result,
+                Err(_) => Err(Error::Timeout {
+                    phase,
+                    proxy_uri: crate::without_credentials(proxy_uri),
+                }),
+            },
+            None => future.await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpListener};
+    use std::thread;
+    use std::time::Duration;
+
+    use hyper_util::client::legacy::connect::HttpConnector;
+    use tokio::runtime::Runtime;
+    use tower_service::Service;
+
+    use super::{TimeoutPhase, Timeouts};
+    use crate::{BoxConnector, Error, Intercept, Proxy, ProxyConnector};
+
+    /// Runs a proxy answering the request of its first connection with `response`, and then
+    /// going silent
+    fn mock_proxy(response: &'static [u8]) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut sock, _) = listener.accept().unwrap();
+            let mut buf = [0u8; 4096];
+            let _ = sock.read(&mut buf).unwrap();
+            sock.write_all(response).unwrap();
+            while sock.read(&mut buf).is_ok_and(|n| n > 0) {}
+        });
+        addr
+    }
+
+    fn timeouts(phase: TimeoutPhase) -> Timeouts {
+        let mut timeouts = Timeouts::new();
+        let timeout = Some(Duration::from_millis(50));
+        match phase {
+            TimeoutPhase::Connect => timeouts.set_connect(timeout),
+            TimeoutPhase::Handshake => timeouts.set_handshake(timeout),
+            TimeoutPhase::TlsHandshake | TimeoutPhase::TargetTlsHandshake => {
+                timeouts.set_tls_handshake(timeout)
+            }
+        }
+        timeouts
+    }
+
+    #[test]
+    fn test_handshake_timeout() {
+        let addr = mock_proxy(b"");
+        let uri = format!("http://john:secret@{}", addr).parse().unwrap();
+        let mut proxy = Proxy::new(Intercept::All, uri);
+        proxy.force_connect();
+        proxy.set_timeouts(timeouts(TimeoutPhase::Handshake));
+        let mut connector = ProxyConnector::unsecured(BoxConnector(HttpConnector::new()));
+        connector.add_proxy(proxy);
+
+        let result = Runtime::new()
+            .unwrap()
+            .block_on(connector.call("http://example.org/".parse().unwrap()));
+        match result {
+            Err(Error::Timeout { phase, proxy_uri }) => {
+                assert_eq!(phase, TimeoutPhase::Handshake);
+                // reported without the credentials
+                assert_eq!(
+                    proxy_uri,
+                    format!("http://{}", addr).parse::<http::Uri>().unwrap()
+                );
+            }
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_connector_timeouts() {
+        let addr = mock_proxy(b"");
+        let mut proxy = Proxy::new(Intercept::All, format!("http://{}", addr).parse().unwrap());
+        proxy.force_connect();
+        // the timeouts the proxy leaves unset are the ones of the connector
+        proxy.set_timeouts(timeouts(TimeoutPhase::Connect));
+        let mut connector = ProxyConnector::unsecured(BoxConnector(HttpConnector::new()));
+        connector.add_proxy(proxy);
+        connector.set_timeouts(timeouts(TimeoutPhase::Handshake));
+
+        let result = Runtime::new()
+            .unwrap()
+            .block_on(connector.call("http://example.org/".parse().unwrap()));
+        assert!(matches!(
+            result,
+            Err(Error::Timeout {
+                phase: TimeoutPhase::Handshake,
+                ..
+            })
+        ));
+    }
+
+    #[cfg(any(feature = "tls", feature = "rustls-base", feature = "openssl-tls"))]
+    #[test]
+    fn test_tls_handshake_timeout() {
+        let addr = mock_proxy(b"HTTP/1.1 200 OK\r\n\r\n");
+        let mut proxy = Proxy::new(Intercept::All, format!("http://{}", addr).parse().unwrap());
+        proxy.set_timeouts(timeouts(TimeoutPhase::TargetTlsHandshake));
+        let mut connector = ProxyConnector::new(BoxConnector(HttpConnector::new())).unwrap();
+        connector.add_proxy(proxy);
+
+        let result = Runtime::new()
+            .unwrap()
+            .block_on(connector.call("https://example.org/".parse().unwrap()));
+        assert!(matches!(
+            result,
+            Err(Error::Timeout {
+                phase: TimeoutPhase::TargetTlsHandshake,
+                ..
+            })
+        ));
+    }
+}
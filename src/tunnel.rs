@@ -1,63 +1,191 @@
-use bytes::{buf::Buf, BytesMut};
-use http::{HeaderMap, Uri};
-use std::fmt::{self, Display, Formatter};
+use bytes::{Buf, BytesMut};
+use http::header::{
+    HeaderName, HeaderValue, CONNECTION, CONTENT_LENGTH, LOCATION, TRANSFER_ENCODING,
+};
+use http::{HeaderMap, StatusCode, Uri};
 use std::future::Future;
 use std::pin::Pin;
-use std::task::{Context, Poll};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
+use crate::auth::{Authenticator, Exchange};
 use crate::error::Error;
+use crate::rewind::Rewind;
+
+/// Opens a new connection to the proxy, when it closed the previous one
+pub(crate) type Reconnect<S> =
+    Box<dyn FnMut() -> Pin<Box<dyn Future<Output = Result<S, Error>> + Send>> + Send>;
+
+/// The successful response of a proxy to a CONNECT request
+///
+/// It is available from [`ProxyStream::connect_response`](crate::ProxyStream::connect_response),
+/// and from the extensions of the responses received through the tunnel.
+///
+/// # Example
+/// ```rust,no_run
+/// # async fn run(res: http::Response<()>) {
+/// use hyper_proxy::ConnectResponse;
+///
+/// if let Some(connect) = res.extensions().get::<ConnectResponse>() {
+///     println!("egress ip: {:?}", connect.headers().get("x-egress-ip"));
+/// }
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct ConnectResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+}
 
-macro_rules! try_ready {
-    ($x:expr) => {
-        match $x {
-            core::task::Poll::Ready(Ok(x)) => x,
-            core::task::Poll::Ready(Err(e)) => return core::task::Poll::Ready(Err(e.into())),
-            core::task::Poll::Pending => return core::task::Poll::Pending,
-        }
-    };
+impl ConnectResponse {
+    /// Get the status of the response
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    /// Get the headers of the response
+    pub fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
 }
 
-pub(crate) struct TunnelConnect {
-    proxy_uri: Uri,
-    buf: BytesMut,
+/// The limits of the head of a proxy response
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Limits {
+    pub(crate) max_head_size: usize,
+    pub(crate) max_headers: usize,
 }
 
-impl TunnelConnect {
-    /// Change stream
-    pub fn with_stream<S>(self, stream: S) -> Tunnel<S> {
-        Tunnel {
-            proxy_uri: self.proxy_uri,
-            buf: self.buf,
-            stream: Some(stream),
-            state: TunnelState::Writing,
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_head_size: 64 * 1024,
+            max_headers: 100,
         }
     }
 }
 
-pub(crate) struct Tunnel<S> {
+pub(crate) struct TunnelConnect {
     proxy_uri: Uri,
-    buf: BytesMut,
-    stream: Option<S>,
-    state: TunnelState,
+    target: String,
+    headers: HeaderMap,
+    authenticator: Option<Authenticator>,
+    limits: Limits,
 }
 
-#[derive(Debug)]
-enum TunnelState {
-    Writing,
-    Reading,
-}
+impl TunnelConnect {
+    /// Answer 407 responses with this authenticator
+    pub fn authenticator(mut self, authenticator: Option<Authenticator>) -> TunnelConnect {
+        self.authenticator = authenticator;
+        self
+    }
 
-struct HeadersDisplay<'a>(&'a HeaderMap);
+    /// Reject responses whose head exceeds these limits
+    pub fn limits(mut self, limits: Limits) -> TunnelConnect {
+        self.limits = limits;
+        self
+    }
 
-impl<'a> Display for HeadersDisplay<'a> {
-    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
-        for (key, value) in self.0 {
-            let value_str = value.to_str().map_err(|_| fmt::Error)?;
-            write!(f, "{}: {}\r\n", key.as_str(), value_str)?;
+    /// Change stream, reconnecting with `reconnect` if the proxy closes the connection while
+    /// authenticating
+    ///
+    /// The bytes read past the response are replayed by the returned stream.
+    pub async fn with_reconnect<S>(
+        self,
+        mut stream: S,
+        mut reconnect: Option<Reconnect<S>>,
+    ) -> Result<(Rewind<S>, ConnectResponse), Error>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut authorization = self
+            .authenticator
+            .as_ref()
+            .and_then(|a| a.preemptive("CONNECT", &self.target));
+        let mut exchange = Exchange::new(true);
+        loop {
+            let request = self.request(authorization.as_ref());
+            stream.write_all(&request).await?;
+            let response = read_response(&mut stream, &self.limits).await?;
+
+            match response.code {
+                // connect tunnel on any 2xx
+                200..=299 => {
+                    let connect_response = ConnectResponse {
+                        status: StatusCode::from_u16(response.code)
+                            .map_err(|_| Error::UnsuccessfulTunnel(response.head.clone()))?,
+                        headers: response.headers,
+                    };
+                    let stream = Rewind::new_buffered(stream, response.body.freeze());
+                    return Ok((stream, connect_response));
+                }
+                // error with location on 301
+                301..=399 => {
+                    return match response.headers.get(LOCATION).and_then(|l| l.to_str().ok()) {
+                        Some(location) => {
+                            let location = location
+                                .parse()
+                                .map_err(|_| Error::UnsuccessfulTunnel(response.head.clone()))?;
+                            Err(Error::ProxyRedirect {
+                                status_code: response.code,
+                                proxy_uri: Box::new(self.proxy_uri),
+                                location: Box::new(location),
+                            })
+                        }
+                        None => Err(Error::MissingProxyRedirectLocation {
+                            code: response.code,
+                        }),
+                    }
+                }
+                // authentication required
+                407 => {
+                    let authenticator = match &self.authenticator {
+                        Some(authenticator) => authenticator,
+                        None => return Err(Error::ProxyAuthenticationRequired),
+                    };
+                    let reusable =
+                        response.keep_alive && discard_body(&mut stream, &response).await?;
+                    if !reusable {
+                        exchange.reconnected();
+                    }
+                    authorization = Some(
+                        authenticator
+                            .respond(
+                                &mut exchange,
+                                &self.proxy_uri,
+                                &response.headers,
+                                "CONNECT",
+                                &self.target,
+                            )
+                            .await?,
+                    );
+                    if !reusable {
+                        stream = match reconnect.as_mut() {
+                            Some(reconnect) => reconnect().await?,
+                            None => return Err(Error::ProxyAuthenticationRequired),
+                        };
+                    }
+                }
+                _ => return Err(Error::UnsuccessfulTunnel(response.head)),
+            }
         }
+    }
 
-        Ok(())
+    fn request(&self, authorization: Option<&HeaderValue>) -> Vec<u8> {
+        let mut headers = self.headers.clone();
+        if let Some(authorization) = authorization {
+            headers.insert(http::header::PROXY_AUTHORIZATION, authorization.clone());
+        }
+        let mut request =
+            format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", self.target).into_bytes();
+        // values are written as is, they may hold opaque bytes
+        for (key, value) in &headers {
+            request.extend_from_slice(key.as_str().as_bytes());
+            request.extend_from_slice(b": ");
+            request.extend_from_slice(value.as_bytes());
+            request.extend_from_slice(b"\r\n");
+        }
+        request.extend_from_slice(b"\r\n");
+        request
     }
 }
 
@@ -68,134 +196,350 @@ pub(crate) fn new(
     port: u16,
     headers: &HeaderMap,
 ) -> TunnelConnect {
-    let buf = format!(
-        "CONNECT {0}:{1} HTTP/1.1\r\n\
-         Host: {0}:{1}\r\n\
-         {2}\
-         \r\n",
-        target_host,
-        port,
-        HeadersDisplay(headers)
-    )
-    .into_bytes();
-
     TunnelConnect {
         proxy_uri: proxy_uri.to_owned(),
-        buf: buf.as_slice().into(),
+        target: format!("{}:{}", target_host, port),
+        headers: headers.clone(),
+        authenticator: None,
+        limits: Limits::default(),
     }
 }
 
-impl<S: AsyncRead + AsyncWrite + Unpin> Future for Tunnel<S> {
-    type Output = Result<S, Error>;
+/// The head of a proxy response, and the part of its body read along
+struct Response {
+    code: u16,
+    headers: HeaderMap,
+    keep_alive: bool,
+    head: String,
+    body: BytesMut,
+}
 
-    fn poll(self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
-        if self.stream.is_none() {
-            panic!("must not poll after future is complete")
+/// Reads the final response of the proxy, skipping the interim 1xx responses
+///
+/// The head is only parsed once its end was read, and the bytes already scanned for it are not
+/// scanned again. The interim responses count against the maximum head size, so that a proxy
+/// cannot keep sending them.
+async fn read_response<S>(stream: &mut S, limits: &Limits) -> Result<Response, Error>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut buf = BytesMut::with_capacity(1024);
+    let mut scanned = 0;
+    // the size of the interim responses skipped
+    let mut interim = 0;
+    loop {
+        if !ends_head(&buf[scanned..]) {
+            if interim + buf.len() >= limits.max_head_size {
+                return Err(Error::ProxyResponseTooLarge(limits.max_head_size));
+            }
+            // the end of the head may straddle two reads
+            scanned = buf.len().saturating_sub(2);
+            if stream.read_buf(&mut buf).await? == 0 {
+                return Err(Error::UnexpectedEOF);
+            }
+            continue;
         }
 
-        let this = self.get_mut();
-
-        loop {
-            if let TunnelState::Writing = &this.state {
-                let fut = this.stream.as_mut().unwrap().write_buf(&mut this.buf);
-                futures_util::pin_mut!(fut);
-                let n = try_ready!(fut.poll(ctx));
-
-                if !this.buf.has_remaining() {
-                    this.state = TunnelState::Reading;
-                    this.buf.truncate(0);
-                } else if n == 0 {
-                    return Poll::Ready(Err(Error::UnexpectedEOF));
-                }
-            } else {
-                let fut = this.stream.as_mut().unwrap().read_buf(&mut this.buf);
-                futures_util::pin_mut!(fut);
-                let n = try_ready!(fut.poll(ctx));
-
-                let mut headers = [httparse::EMPTY_HEADER; 16];
-                let mut response = httparse::Response::new(&mut headers);
-
-                if n == 0 {
-                    return Poll::Ready(Err(Error::UnexpectedEOF));
-                } else {
-                    let read = &this.buf[..];
-
-                    let parsed = match response.parse(read) {
-                        Ok(parsed) => parsed,
-                        Err(err) => {
-                            return Poll::Ready(Err(Error::UnsuccessfulTunnel(format!(
-                                "failed to parse proxy http response ({err})"
-                            ))))
-                        }
-                    };
+        let mut headers = vec![httparse::EMPTY_HEADER; limits.max_headers];
+        let mut response = httparse::Response::new(&mut headers);
+        let len = match response.parse(&buf) {
+            Ok(httparse::Status::Complete(len)) => len,
+            Ok(httparse::Status::Partial) => {
+                scanned = buf.len().saturating_sub(2);
+                continue;
+            }
+            Err(httparse::Error::TooManyHeaders) => {
+                return Err(Error::TooManyProxyResponseHeaders(limits.max_headers))
+            }
+            Err(err) => {
+                return Err(Error::UnsuccessfulTunnel(format!(
+                    "failed to parse proxy http response ({err})"
+                )))
+            }
+        };
+        if interim + len > limits.max_head_size {
+            return Err(Error::ProxyResponseTooLarge(limits.max_head_size));
+        }
+        // an interim response, followed by another one
+        let code = response.code.unwrap_or_default();
+        if (100..200).contains(&code) && code != 101 {
+            interim += len;
+            buf.advance(len);
+            scanned = 0;
+            continue;
+        }
 
-                    if parsed.is_complete() {
-                        match response.code {
-                            Some(200) => {
-                                // connect tunnel on 200
-                                if read.ends_with(b"\r\n\r\n") {
-                                    return Poll::Ready(Ok(this.stream.take().unwrap()));
-                                }
-                            }
-                            Some(301..=399) => {
-                                // error with location on 301
-                                let location = response
-                                    .headers
-                                    .iter()
-                                    .find(|h| h.name.to_lowercase() == "location");
-                                if let Some(location) = location
-                                    .and_then(|l| std::convert::TryInto::try_into(l.value).ok())
-                                {
-                                    return Poll::Ready(Err(Error::ProxyRedirect {
-                                        status_code: response.code.unwrap(),
-                                        proxy_uri: this.proxy_uri.to_owned(),
-                                        location,
-                                    }));
-                                } else if let Some(code) = response.code {
-                                    return Poll::Ready(Err(Error::MissingProxyRedirectLocation {
-                                        code,
-                                    }));
-                                } else {
-                                    return Poll::Ready(Err(Error::UnsuccessfulTunnel(
-                                        String::from_utf8_lossy(read).to_string(),
-                                    )));
-                                }
-                            }
-                            // error with authentication required
-                            Some(407) => {
-                                return Poll::Ready(Err(Error::ProxyAuthenticationRequired));
-                            }
-                            _ => {
-                                // let len = read.len().min(16);
-                                return Poll::Ready(Err(Error::UnsuccessfulTunnel(
-                                    String::from_utf8_lossy(read).to_string(),
-                                )));
-                            }
-                        }
-                    }
-                }
+        let mut header_map = HeaderMap::new();
+        for header in response.headers.iter() {
+            if let (Ok(name), Ok(value)) = (
+                HeaderName::from_bytes(header.name.as_bytes()),
+                HeaderValue::from_bytes(header.value),
+            ) {
+                header_map.append(name, value);
             }
         }
+        let connection = |value: &str| {
+            header_map
+                .get_all(CONNECTION)
+                .iter()
+                .chain(header_map.get_all("proxy-connection"))
+                .filter_map(|v| v.to_str().ok())
+                .any(|v| v.split(',').any(|t| t.trim().eq_ignore_ascii_case(value)))
+        };
+        let keep_alive = match response.version {
+            Some(0) => connection("keep-alive"),
+            _ => !connection("close"),
+        };
+
+        return Ok(Response {
+            code,
+            keep_alive,
+            head: String::from_utf8_lossy(&buf[..len]).into_owned(),
+            body: buf.split_off(len),
+            headers: header_map,
+        });
+    }
+}
+
+/// Returns true if `buf` contains an empty line, ending a head
+fn ends_head(buf: &[u8]) -> bool {
+    buf.windows(2).any(|w| w == b"\n\n") || buf.windows(3).any(|w| w == b"\n\r\n")
+}
+
+/// Reads the body of `response` to reuse the connection, returns false if it cannot be reused
+async fn discard_body<S>(stream: &mut S, response: &Response) -> Result<bool, Error>
+where
+    S: AsyncRead + Unpin,
+{
+    // a body delimited by the end of the connection, or chunked, is not worth reading
+    let length = match response.headers.get(CONTENT_LENGTH) {
+        _ if response.headers.contains_key(TRANSFER_ENCODING) => return Ok(false),
+        Some(length) => length.to_str().ok().and_then(|l| l.parse::<usize>().ok()),
+        None => None,
+    };
+    let mut remaining = match length {
+        Some(length) if length >= response.body.len() => length - response.body.len(),
+        _ => return Ok(false),
+    };
+
+    let mut buf = BytesMut::with_capacity(remaining.min(8192));
+    while remaining > 0 {
+        buf.clear();
+        let n = (&mut *stream)
+            .take(remaining.min(8192) as u64)
+            .read_buf(&mut buf)
+            .await?;
+        if n == 0 {
+            return Ok(false);
+        }
+        remaining -= n;
     }
+    Ok(true)
 }
 
 #[cfg(test)]
 mod tests {
     use crate::error::Error;
 
-    use super::{HeaderMap, Tunnel};
-    use futures_util::future::TryFutureExt;
+    use super::{ConnectResponse, HeaderMap, Limits, Reconnect, Rewind};
+    use crate::auth::{Authenticator, CredentialsProvider};
+    use crate::{ntlm, Challenge, ProxyAuthContext, ProxyAuthenticator, ProxyCredentials};
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+    use futures_util::future::{Future, TryFutureExt};
     use std::io::{Read, Write};
-    use std::net::TcpListener;
+    use std::net::{SocketAddr, TcpListener};
+    use std::sync::{Arc, Mutex};
     use std::thread;
+    use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
     use tokio::net::TcpStream;
     use tokio::runtime::Runtime;
 
-    fn tunnel<S>(conn: S, host: String, port: u16) -> Tunnel<S> {
+    fn tunnel<S>(conn: S, host: String, port: u16) -> impl Future<Output = Result<Rewind<S>, Error>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let fake_uri = http::Uri::from_static("http://example.com");
+        super::new(&fake_uri, &host, port, &HeaderMap::new())
+            .with_reconnect(conn, None)
+            .map_ok(|(stream, _)| stream)
+    }
+
+    /// Runs a proxy answering each CONNECT request with the next response, on a new connection
+    /// when the previous response closed it, and returns the requests it received
+    fn mock_auth_proxy<R>(responses: Vec<R>) -> (SocketAddr, thread::JoinHandle<Vec<String>>)
+    where
+        R: AsRef<str> + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let mut requests = Vec::new();
+            let mut sock = None;
+            for response in responses {
+                let response = response.as_ref();
+                if sock.is_none() {
+                    sock = Some(listener.accept().unwrap().0);
+                }
+                let s = sock.as_mut().unwrap();
+                let mut buf = [0u8; 4096];
+                let n = s.read(&mut buf).unwrap();
+                requests.push(String::from_utf8_lossy(&buf[..n]).into_owned());
+                s.write_all(response.as_bytes()).unwrap();
+                if response.contains("Connection: close") {
+                    sock = None;
+                }
+            }
+            requests
+        });
+        (addr, handle)
+    }
+
+    fn authenticated_tunnel(
+        addr: SocketAddr,
+        max_retries: usize,
+        realms: Arc<Mutex<Vec<Option<String>>>>,
+    ) -> Result<Rewind<TcpStream>, Error> {
+        authenticated_tunnel_as(addr, "john", max_retries, realms)
+    }
+
+    fn authenticated_tunnel_as(
+        addr: SocketAddr,
+        username: &'static str,
+        max_retries: usize,
+        realms: Arc<Mutex<Vec<Option<String>>>>,
+    ) -> Result<Rewind<TcpStream>, Error> {
+        let provider = CredentialsProvider::new(move |challenge: crate::ProxyChallenge| {
+            realms
+                .lock()
+                .unwrap()
+                .push(challenge.realm().map(String::from));
+            let password = format!("secret{}", challenge.attempt());
+            async move { Some(ProxyCredentials::new(username, password)) }
+        });
+        let reconnect: Reconnect<TcpStream> =
+            Box::new(move || Box::pin(async move { Ok(TcpStream::connect(addr).await?) }));
+
+        let fake_uri = http::Uri::from_static("http://example.com");
+        let connect = super::new(&fake_uri, "example.org", 443, &HeaderMap::new()).authenticator(
+            Some(Authenticator {
+                credentials: None,
+                provider: Some(provider),
+                max_retries,
+                authenticators: Vec::new(),
+                digest: Arc::new(Mutex::new(None)),
+            }),
+        );
+        Runtime::new().unwrap().block_on(async move {
+            let tcp = TcpStream::connect(addr).await?;
+            let (stream, _) = connect.with_reconnect(tcp, Some(reconnect)).await?;
+            Ok(stream)
+        })
+    }
+
+    /// A `Negotiate` authenticator answering `client<n>` to the n-th challenge of a connection,
+    /// and recording the tokens of the proxy
+    struct MockNegotiate {
+        tokens: Arc<Mutex<Vec<Option<String>>>>,
+    }
+
+    struct MockContext {
+        round: usize,
+        tokens: Arc<Mutex<Vec<Option<String>>>>,
+    }
+
+    impl ProxyAuthenticator for MockNegotiate {
+        fn scheme(&self) -> &str {
+            "Negotiate"
+        }
+
+        fn start(
+            &self,
+            _proxy_uri: &http::Uri,
+            target: &str,
+        ) -> Result<Box<dyn ProxyAuthContext>, Error> {
+            assert_eq!(target, "example.org:443");
+            Ok(Box::new(MockContext {
+                round: 0,
+                tokens: self.tokens.clone(),
+            }))
+        }
+    }
+
+    impl ProxyAuthContext for MockContext {
+        fn next_token(&mut self, challenge: &Challenge) -> Result<Option<String>, Error> {
+            self.tokens
+                .lock()
+                .unwrap()
+                .push(challenge.token68().map(String::from));
+            self.round += 1;
+            Ok(Some(format!("client{}", self.round)))
+        }
+    }
+
+    fn negotiate_tunnel(
+        addr: SocketAddr,
+        tokens: Arc<Mutex<Vec<Option<String>>>>,
+    ) -> Result<Rewind<TcpStream>, Error> {
+        let reconnect: Reconnect<TcpStream> =
+            Box::new(move || Box::pin(async move { Ok(TcpStream::connect(addr).await?) }));
         let fake_uri = http::Uri::from_static("http://example.com");
-        super::new(&fake_uri, &host, port, &HeaderMap::new()).with_stream(conn)
+        let connect = super::new(&fake_uri, "example.org", 443, &HeaderMap::new()).authenticator(
+            Some(Authenticator {
+                credentials: None,
+                provider: None,
+                max_retries: 3,
+                authenticators: vec![Arc::new(MockNegotiate { tokens })],
+                digest: Arc::new(Mutex::new(None)),
+            }),
+        );
+        Runtime::new().unwrap().block_on(async move {
+            let tcp = TcpStream::connect(addr).await?;
+            let (stream, _) = connect.with_reconnect(tcp, Some(reconnect)).await?;
+            Ok(stream)
+        })
     }
 
+    const CHALLENGE: &str = "HTTP/1.1 407 Proxy Authentication Required\r\n\
+        Proxy-Authenticate: Basic realm=\"corp\"\r\n\
+        Content-Length: 5\r\n\
+        \r\n\
+        nope!";
+    const CHALLENGE_CLOSE: &str = "HTTP/1.1 407 Proxy Authentication Required\r\n\
+        Proxy-Authenticate: Basic realm=\"corp\"\r\n\
+        Connection: close\r\n\
+        \r\n";
+    const OK: &str = "HTTP/1.1 200 OK\r\n\r\n";
+    const DIGEST: &str = "HTTP/1.1 407 Proxy Authentication Required\r\n\
+        Proxy-Authenticate: Basic realm=\"corp\"\r\n\
+        Proxy-Authenticate: Digest realm=\"corp\", nonce=\"n1\", qop=\"auth\", algorithm=SHA-256\r\n\
+        Content-Length: 0\r\n\
+        \r\n";
+    const DIGEST_STALE: &str = "HTTP/1.1 407 Proxy Authentication Required\r\n\
+        Proxy-Authenticate: Digest realm=\"corp\", nonce=\"n2\", qop=\"auth\", algorithm=SHA-256, stale=true\r\n\
+        Content-Length: 0\r\n\
+        \r\n";
+
+    const NTLM: &str = "HTTP/1.1 407 Proxy Authentication Required\r\n\
+        Proxy-Authenticate: NTLM\r\n\
+        Proxy-Authenticate: Basic realm=\"corp\"\r\n\
+        Content-Length: 0\r\n\
+        \r\n";
+
+    const NEGOTIATE: &str = "HTTP/1.1 407 Proxy Authentication Required\r\n\
+        Proxy-Authenticate: Basic realm=\"corp\"\r\n\
+        Proxy-Authenticate: Negotiate\r\n\
+        Content-Length: 0\r\n\
+        \r\n";
+    const NEGOTIATE_CONTINUE: &str = "HTTP/1.1 407 Proxy Authentication Required\r\n\
+        Proxy-Authenticate: Negotiate server1\r\n\
+        Content-Length: 0\r\n\
+        \r\n";
+    const NEGOTIATE_CLOSE: &str = "HTTP/1.1 407 Proxy Authentication Required\r\n\
+        Proxy-Authenticate: Negotiate server1\r\n\
+        Connection: close\r\n\
+        \r\n";
+
     macro_rules! mock_tunnel {
         () => {{
             mock_tunnel!(
@@ -275,4 +619,285 @@ mod tests {
 
         core.block_on(work).unwrap_err();
     }
+
+    fn limited_tunnel(
+        addr: SocketAddr,
+        limits: Limits,
+    ) -> Result<(Rewind<TcpStream>, ConnectResponse), Error> {
+        let fake_uri = http::Uri::from_static("http://example.com");
+        let host = addr.ip().to_string();
+        let connect = super::new(&fake_uri, &host, addr.port(), &HeaderMap::new()).limits(limits);
+        Runtime::new().unwrap().block_on(async move {
+            let tcp = TcpStream::connect(addr).await?;
+            connect.with_reconnect(tcp, None).await
+        })
+    }
+
+    #[test]
+    fn test_tunnel_any_success() {
+        let addr = mock_tunnel!(b"HTTP/1.1 204 No Content\r\n\r\n");
+        limited_tunnel(addr, Limits::default()).unwrap();
+    }
+
+    #[test]
+    fn test_tunnel_response() {
+        let addr = mock_tunnel!(
+            b"\
+            HTTP/1.1 200 Connection established\r\n\
+            Via: 1.1 proxy\r\n\
+            X-Egress-IP: 192.0.2.1\r\n\
+            \r\n\
+            "
+        );
+        let (_, response) = limited_tunnel(addr, Limits::default()).unwrap();
+        assert_eq!(response.status(), http::StatusCode::OK);
+        assert_eq!(response.headers()["via"], "1.1 proxy");
+        assert_eq!(response.headers()["x-egress-ip"], "192.0.2.1");
+    }
+
+    #[test]
+    fn test_tunnel_interim_responses() {
+        let addr = mock_tunnel!(
+            b"\
+            HTTP/1.1 100 Continue\r\n\
+            \r\n\
+            HTTP/1.1 103 Early Hints\r\n\
+            Link: </style.css>; rel=preload\r\n\
+            \r\n\
+            HTTP/1.1 200 OK\r\n\
+            \r\n\
+            "
+        );
+        limited_tunnel(addr, Limits::default()).unwrap();
+    }
+
+    #[test]
+    fn test_tunnel_early_data() {
+        let addr = mock_tunnel!(b"HTTP/1.1 200 OK\r\n\r\nhello");
+
+        let core = Runtime::new().unwrap();
+        let host = addr.ip().to_string();
+        let port = addr.port();
+        let hello = core.block_on(async move {
+            let tcp = TcpStream::connect(&addr).await?;
+            let mut stream = tunnel(tcp, host, port).await?;
+            // read along with the response, and replayed
+            assert_eq!(stream.buffered(), b"hello");
+            let mut hello = String::new();
+            stream.read_to_string(&mut hello).await?;
+            Ok::<_, Error>(hello)
+        });
+        assert_eq!(hello.unwrap(), "hello");
+    }
+
+    #[test]
+    fn test_tunnel_response_too_large() {
+        let addr = mock_tunnel!(
+            b"\
+            HTTP/1.1 200 OK\r\n\
+            Server: a proxy with a very long name, longer than the limit\r\n\
+            \r\n\
+            "
+        );
+        let limits = Limits {
+            max_head_size: 32,
+            ..Limits::default()
+        };
+        let result = limited_tunnel(addr, limits);
+        assert!(matches!(result, Err(Error::ProxyResponseTooLarge(32))));
+    }
+
+    #[test]
+    fn test_tunnel_too_many_interim_responses() {
+        let addr = mock_tunnel!(&b"HTTP/1.1 100 Continue\r\n\r\n".repeat(100));
+        let limits = Limits {
+            max_head_size: 256,
+            ..Limits::default()
+        };
+        let result = limited_tunnel(addr, limits);
+        assert!(matches!(result, Err(Error::ProxyResponseTooLarge(256))));
+    }
+
+    #[test]
+    fn test_tunnel_too_many_headers() {
+        let addr = mock_tunnel!(
+            b"\
+            HTTP/1.1 200 OK\r\n\
+            Server: proxy\r\n\
+            Via: 1.1 proxy\r\n\
+            Date: Sat, 17 Oct 2026 10:00:00 GMT\r\n\
+            \r\n\
+            "
+        );
+        let limits = Limits {
+            max_headers: 2,
+            ..Limits::default()
+        };
+        let result = limited_tunnel(addr, limits);
+        assert!(matches!(result, Err(Error::TooManyProxyResponseHeaders(2))));
+    }
+
+    #[test]
+    fn test_tunnel_auth_same_connection() {
+        let (addr, proxy) = mock_auth_proxy(vec![CHALLENGE, OK]);
+        let realms = Arc::new(Mutex::new(Vec::new()));
+        authenticated_tunnel(addr, 3, realms.clone()).unwrap();
+
+        let requests = proxy.join().unwrap();
+        assert!(!requests[0].contains("proxy-authorization"));
+        // base64 of john:secret1
+        assert!(requests[1].contains("proxy-authorization: Basic am9objpzZWNyZXQx\r\n"));
+        assert_eq!(*realms.lock().unwrap(), vec![Some("corp".to_string())]);
+    }
+
+    #[test]
+    fn test_tunnel_auth_new_connection() {
+        let (addr, proxy) = mock_auth_proxy(vec![CHALLENGE_CLOSE, CHALLENGE, OK]);
+        let realms = Arc::new(Mutex::new(Vec::new()));
+        authenticated_tunnel(addr, 3, realms.clone()).unwrap();
+
+        let requests = proxy.join().unwrap();
+        assert!(requests[1].contains("proxy-authorization: Basic am9objpzZWNyZXQx\r\n"));
+        assert!(requests[2].contains("proxy-authorization: Basic am9objpzZWNyZXQy\r\n"));
+        assert_eq!(realms.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_tunnel_auth_retries() {
+        let (addr, proxy) = mock_auth_proxy(vec![CHALLENGE, CHALLENGE, CHALLENGE]);
+        let realms = Arc::new(Mutex::new(Vec::new()));
+        let err = authenticated_tunnel(addr, 2, realms.clone()).unwrap_err();
+
+        assert!(matches!(err, Error::ProxyAuthenticationRequired));
+        assert_eq!(proxy.join().unwrap().len(), 3);
+        assert_eq!(realms.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_tunnel_auth_without_provider() {
+        let (addr, _proxy) = mock_auth_proxy(vec![CHALLENGE]);
+
+        let core = Runtime::new().unwrap();
+        let work = TcpStream::connect(&addr)
+            .map_err(Error::from)
+            .and_then(|tcp| tunnel(tcp, "example.org".into(), 443));
+
+        assert!(matches!(
+            core.block_on(work),
+            Err(Error::ProxyAuthenticationRequired)
+        ));
+    }
+
+    #[test]
+    fn test_tunnel_auth_digest_stale() {
+        let (addr, proxy) = mock_auth_proxy(vec![DIGEST, DIGEST_STALE, OK]);
+        let realms = Arc::new(Mutex::new(Vec::new()));
+        authenticated_tunnel(addr, 3, realms.clone()).unwrap();
+
+        let requests = proxy.join().unwrap();
+        assert!(requests[1].contains("proxy-authorization: Digest username=\"john\", realm=\"corp\", nonce=\"n1\", uri=\"example.org:443\", algorithm=SHA-256,"));
+        assert!(requests[1].contains("qop=auth, nc=00000001,"));
+        assert!(requests[2].contains("nonce=\"n2\""));
+        assert!(requests[2].contains("qop=auth, nc=00000001,"));
+        // the stale nonce is renewed without asking for credentials again
+        assert_eq!(realms.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_tunnel_auth_digest_non_ascii_username() {
+        let challenge = "HTTP/1.1 407 Proxy Authentication Required\r\n\
+            Proxy-Authenticate: Digest realm=\"p\", nonce=\"n\", qop=\"auth\"\r\n\
+            Content-Length: 0\r\n\
+            \r\n";
+        let (addr, proxy) = mock_auth_proxy(vec![challenge, OK]);
+        let realms = Arc::new(Mutex::new(Vec::new()));
+        authenticated_tunnel_as(addr, "josé", 3, realms).unwrap();
+
+        let requests = proxy.join().unwrap();
+        assert!(requests[1]
+            .contains("proxy-authorization: Digest username*=UTF-8''jos%C3%A9, realm=\"p\","));
+    }
+
+    #[test]
+    fn test_tunnel_opaque_header_bytes() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-user",
+            http::HeaderValue::from_bytes("josé".as_bytes()).unwrap(),
+        );
+        let fake_uri = http::Uri::from_static("http://example.com");
+        let request = super::new(&fake_uri, "example.org", 443, &headers).request(None);
+        assert_eq!(
+            request,
+            "CONNECT example.org:443 HTTP/1.1\r\nHost: example.org:443\r\nx-user: josé\r\n\r\n"
+                .as_bytes()
+        );
+    }
+
+    #[test]
+    fn test_tunnel_auth_ntlm() {
+        let server_challenge = [1, 2, 3, 4, 5, 6, 7, 8];
+        let message = ntlm::tests::challenge_message(server_challenge, &[0, 0, 0, 0]);
+        let challenge = format!(
+            "HTTP/1.1 407 Proxy Authentication Required\r\n\
+            Proxy-Authenticate: NTLM {}\r\n\
+            Content-Length: 0\r\n\
+            \r\n",
+            STANDARD.encode(message)
+        );
+        let (addr, proxy) = mock_auth_proxy(vec![NTLM.to_string(), challenge, OK.to_string()]);
+        let realms = Arc::new(Mutex::new(Vec::new()));
+        authenticated_tunnel(addr, 3, realms.clone()).unwrap();
+
+        // the three messages are exchanged on the same connection
+        let requests = proxy.join().unwrap();
+        assert!(!requests[0].contains("proxy-authorization"));
+        // base64 of the NEGOTIATE message signature and type
+        assert!(requests[1].contains("proxy-authorization: NTLM TlRMTVNTUAAB"));
+        let authenticate = requests[2]
+            .split("proxy-authorization: NTLM ")
+            .nth(1)
+            .and_then(|rest| rest.split("\r\n").next())
+            .unwrap();
+        let authenticate = STANDARD.decode(authenticate).unwrap();
+        assert_eq!(
+            ntlm::tests::verify(&authenticate, "secret1", server_challenge),
+            Some((String::new(), "john".to_string()))
+        );
+        assert_eq!(realms.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_tunnel_auth_custom_scheme() {
+        let (addr, proxy) = mock_auth_proxy(vec![NEGOTIATE, NEGOTIATE_CONTINUE, OK]);
+        let tokens = Arc::new(Mutex::new(Vec::new()));
+        negotiate_tunnel(addr, tokens.clone()).unwrap();
+
+        // the tokens are exchanged on the same connection
+        let requests = proxy.join().unwrap();
+        assert!(!requests[0].contains("proxy-authorization"));
+        assert!(requests[1].contains("proxy-authorization: Negotiate client1\r\n"));
+        assert!(requests[2].contains("proxy-authorization: Negotiate client2\r\n"));
+        assert_eq!(
+            *tokens.lock().unwrap(),
+            vec![None, Some("server1".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_tunnel_auth_custom_scheme_new_connection() {
+        let (addr, proxy) =
+            mock_auth_proxy(vec![NEGOTIATE, NEGOTIATE_CLOSE, NEGOTIATE_CONTINUE, OK]);
+        let tokens = Arc::new(Mutex::new(Vec::new()));
+        negotiate_tunnel(addr, tokens.clone()).unwrap();
+
+        // the exchange starts over on the new connection
+        let requests = proxy.join().unwrap();
+        assert!(requests[2].contains("proxy-authorization: Negotiate client1\r\n"));
+        assert!(requests[3].contains("proxy-authorization: Negotiate client2\r\n"));
+        assert_eq!(
+            *tokens.lock().unwrap(),
+            vec![None, None, Some("server1".to_string())]
+        );
+    }
 }
@@ -0,0 +1,675 @@
+//! Web proxy auto-discovery (WPAD) of PAC files over DNS and HTTP
+//!
+//! The PAC file is looked up at `http://wpad.<domain>/wpad.dat` for each domain derived from the
+//! search domains of the host, e.g. `wpad.eng.example.com` then `wpad.example.com`.
+
+use std::fmt;
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures_util::future::{join, poll_fn};
+use http::header::HOST;
+use http::{Request, Uri};
+use hyper::body::Body;
+use hyper::rt;
+use hyper_util::client::legacy::connect::Connection;
+use tokio::net::UdpSocket;
+use tokio::sync::Mutex;
+use tokio::time::timeout;
+use tower_service::Service;
+
+use crate::{connect, Error, Pac, Timeouts};
+
+/// Delay before retrying a failed discovery, doubled after each failure
+const INITIAL_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Maximum delay between two discoveries
+const MAX_BACKOFF: Duration = Duration::from_secs(3600);
+
+/// Timeout of each DNS query and PAC file download
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Maximum size of a PAC file
+const MAX_PAC_SIZE: usize = 1024 * 1024;
+
+/// Delay after which a discovered PAC file is looked up again
+const TTL: Duration = Duration::from_secs(3600);
+
+/// Second-level labels under which country code domains are registered, e.g. `co.uk`
+const PUBLIC_SECOND_LEVELS: &[&str] = &[
+    "ac", "co", "com", "edu", "go", "gob", "gov", "gv", "ltd", "mil", "ne", "net", "nom", "or",
+    "org", "plc",
+];
+
+enum State {
+    Idle,
+    Found {
+        pac: Pac,
+        expires_at: Instant,
+    },
+    Failed {
+        retry_at: Instant,
+        backoff: Duration,
+    },
+}
+
+/// Discovers the PAC file of the local network with WPAD
+///
+/// The result of the discovery is cached by the `Wpad` (and its clones): once found, the PAC
+/// file is kept for one hour before being discovered again. Failures are retried after a backoff
+/// starting at one minute and doubling up to one hour.
+///
+/// # Example
+/// ```rust,no_run
+/// use hyper_proxy::{ProxyConnector, Wpad};
+/// use hyper_util::client::legacy::connect::HttpConnector;
+///
+/// let mut connector = ProxyConnector::unsecured(HttpConnector::new());
+/// connector.set_wpad(Some(Wpad::new()));
+/// ```
+#[derive(Clone)]
+pub struct Wpad {
+    search_domains: Vec<String>,
+    nameserver: Option<SocketAddr>,
+    port: u16,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    ttl: Duration,
+    state: Arc<Mutex<State>>,
+}
+
+impl fmt::Debug for Wpad {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Wpad")
+            .field("search_domains", &self.search_domains)
+            .field("nameserver", &self.nameserver)
+            .field("port", &self.port)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for Wpad {
+    fn default() -> Self {
+        Wpad::new()
+    }
+}
+
+impl Wpad {
+    /// Creates a discovery using the search domains of `/etc/resolv.conf` and the system resolver
+    pub fn new() -> Wpad {
+        let search_domains = std::fs::read_to_string("/etc/resolv.conf")
+            .map(|conf| search_domains(&conf))
+            .unwrap_or_default();
+        Wpad::with_search_domains(search_domains)
+    }
+
+    /// Creates a discovery using the given search domains and the system resolver
+    pub fn with_search_domains<I, S>(domains: I) -> Wpad
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Wpad {
+            search_domains: domains.into_iter().map(Into::into).collect(),
+            nameserver: None,
+            port: 80,
+            initial_backoff: INITIAL_BACKOFF,
+            max_backoff: MAX_BACKOFF,
+            ttl: TTL,
+            state: Arc::new(Mutex::new(State::Idle)),
+        }
+    }
+
+    /// Resolves the candidates with this DNS server instead of the system resolver
+    pub fn set_nameserver(&mut self, nameserver: Option<SocketAddr>) {
+        self.nameserver = nameserver;
+    }
+
+    /// Set the port the PAC file is fetched from (80 by default)
+    pub fn set_port(&mut self, port: u16) {
+        self.port = port;
+    }
+
+    /// Set the delay before retrying after a first failure, and the maximum delay it doubles to
+    pub fn set_backoff(&mut self, initial: Duration, max: Duration) {
+        self.initial_backoff = initial;
+        self.max_backoff = max;
+    }
+
+    /// Set how long a discovered PAC file is used before being discovered again (one hour by
+    /// default)
+    pub fn set_ttl(&mut self, ttl: Duration) {
+        self.ttl = ttl;
+    }
+
+    /// Get the search domains
+    pub fn search_domains(&self) -> &[String] {
+        &self.search_domains
+    }
+
+    /// Get the host names probed for a PAC file, in order
+    ///
+    /// Each search domain is shortened one label at a time, down to two labels, so that
+    /// `eng.example.com` gives `wpad.eng.example.com` then `wpad.example.com`. Two labels that
+    /// look like a public suffix, such as `co.uk`, are not probed.
+    pub fn candidates(&self) -> Vec<String> {
+        let mut candidates: Vec<String> = Vec::new();
+        for domain in &self.search_domains {
+            let domain = domain.trim_matches('.').to_ascii_lowercase();
+            let labels: Vec<&str> = domain.split('.').filter(|l| !l.is_empty()).collect();
+            for i in 0..labels.len().saturating_sub(1) {
+                if is_public_suffix(&labels[i..]) {
+                    break;
+                }
+                let candidate = format!("wpad.{}", labels[i..].join("."));
+                if !candidates.contains(&candidate) {
+                    candidates.push(candidate);
+                }
+            }
+        }
+        candidates
+    }
+
+    /// Returns the discovered PAC file, fetching it with `connector` if needed
+    ///
+    /// Concurrent calls wait for a single discovery. While backing off after a failure, an
+    /// error is returned immediately.
+    pub async fn discover<C>(&self, mut connector: C) -> Result<Pac, Error>
+    where
+        C: Service<Uri> + Clone + Send + 'static,
+        C::Response: Connection + rt::Read + rt::Write + Send + Unpin + 'static,
+        C::Future: Send + 'static,
+        C::Error: Into<Error>,
+    {
+        let mut state = self.state.lock().await;
+        let backoff = match &*state {
+            State::Found { pac, expires_at } if Instant::now() < *expires_at => {
+                return Ok(pac.clone())
+            }
+            State::Failed { retry_at, backoff } => {
+                if Instant::now() < *retry_at {
+                    return Err(Error::Wpad("no PAC file found, backing off".into()));
+                }
+                (*backoff * 2).min(self.max_backoff)
+            }
+            State::Idle | State::Found { .. } => self.initial_backoff,
+        };
+
+        let mut last_error = Error::Wpad("no search domain to derive WPAD candidates".into());
+        for candidate in self.candidates() {
+            match self.try_candidate(&mut connector, &candidate).await {
+                Ok(pac) => {
+                    *state = State::Found {
+                        pac: pac.clone(),
+                        expires_at: Instant::now() + self.ttl,
+                    };
+                    return Ok(pac);
+                }
+                Err(e) => last_error = e,
+            }
+        }
+
+        *state = State::Failed {
+            retry_at: Instant::now() + backoff,
+            backoff,
+        };
+        Err(last_error)
+    }
+
+    async fn try_candidate<C>(&self, connector: &mut C, host: &str) -> Result<Pac, Error>
+    where
+        C: Service<Uri> + Clone + Send + 'static,
+        C::Response: Connection + rt::Read + rt::Write + Send + Unpin + 'static,
+        C::Future: Send + 'static,
+        C::Error: Into<Error>,
+    {
+        let ip = match self.nameserver {
+            Some(nameserver) => with_timeout(resolve(nameserver, host)).await?,
+            None => {
+                with_timeout(async {
+                    tokio::net::lookup_host((host, self.port))
+                        .await?
+                        .next()
+                        .map(|addr| addr.ip())
+                        .ok_or_else(|| Error::Wpad(format!("{} does not resolve", host)))
+                })
+                .await?
+            }
+        };
+        let script = with_timeout(fetch(connector, SocketAddr::new(ip, self.port), host)).await?;
+        Pac::new(script)
+    }
+}
+
+async fn with_timeout<T, F: Future<Output = Result<T, Error>>>(f: F) -> Result<T, Error> {
+    timeout(REQUEST_TIMEOUT, f)
+        .await
+        .map_err(|_| Error::Wpad("request timed out".into()))?
+}
+
+/// Downloads `/wpad.dat` from `addr`
+async fn fetch<C>(connector: &mut C, addr: SocketAddr, host: &str) -> Result<String, Error>
+where
+    C: Service<Uri> + Clone + Send + 'static,
+    C::Response: Connection + rt::Read + rt::Write + Send + Unpin + 'static,
+    C::Future: Send + 'static,
+    C::Error: Into<Error>,
+{
+    poll_fn(|cx| connector.poll_ready(cx))
+        .await
+        .map_err(Into::into)?;
+    let uri = Uri::builder()
+        .scheme("http")
+        .authority(addr.to_string())
+        .path_and_query("/")
+        .build()?;
+    let stream = connect(connector, &None, &None, &Timeouts::new(), None, uri).await?;
+
+    let (mut sender, conn) = hyper::client::conn::http1::handshake(stream)
+        .await
+        .map_err(|e| Error::Wpad(e.to_string()))?;
+    let req = Request::get("/wpad.dat")
+        .header(HOST, host)
+        .body(String::new())?;
+    let response = async move {
+        let res = sender
+            .send_request(req)
+            .await
+            .map_err(|e| Error::Wpad(e.to_string()))?;
+        if !res.status().is_success() {
+            return Err(Error::Wpad(format!(
+                "{} answered {} for /wpad.dat",
+                host,
+                res.status()
+            )));
+        }
+        let mut body = res.into_body();
+        let mut script = Vec::new();
+        while let Some(frame) = poll_fn(|cx| Pin::new(&mut body).poll_frame(cx)).await {
+            let frame = frame.map_err(|e| Error::Wpad(e.to_string()))?;
+            if let Some(data) = frame.data_ref() {
+                script.extend_from_slice(data);
+                if script.len() > MAX_PAC_SIZE {
+                    return Err(Error::Wpad(format!("{} PAC file is too large", host)));
+                }
+            }
+        }
+        String::from_utf8(script)
+            .map_err(|_| Error::Wpad(format!("{} PAC file is not utf-8", host)))
+    };
+    // the connection stops once the request is dropped
+    join(response, conn).await.0
+}
+
+/// Whether `labels` look like a country code domain such as `co.uk`, under which anyone registers
+fn is_public_suffix(labels: &[&str]) -> bool {
+    match labels {
+        [second, top] => top.len() == 2 && PUBLIC_SECOND_LEVELS.contains(second),
+        _ => false,
+    }
+}
+
+/// Extracts the search domains of a `resolv.conf` file
+fn search_domains(conf: &str) -> Vec<String> {
+    let mut domains = Vec::new();
+    for line in conf.lines() {
+        let mut words = line.split_whitespace();
+        match words.next() {
+            // the last `search` or `domain` line wins
+            Some("search") | Some("domain") => {
+                domains = words.map(String::from).collect();
+            }
+            _ => (),
+        }
+    }
+    domains
+}
+
+/// Resolves the ipv4 address of `host` with a single DNS query
+async fn resolve(nameserver: SocketAddr, host: &str) -> Result<IpAddr, Error> {
+    let mut id = [0; 2];
+    getrandom::getrandom(&mut id).map_err(|e| Error::Wpad(e.to_string()))?;
+    let mut query = Vec::with_capacity(host.len() + 18);
+    query.extend_from_slice(&id);
+    // recursion desired, one question
+    query.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in host.split('.').filter(|l| !l.is_empty()) {
+        if label.len() > 63 {
+            return Err(Error::Wpad(format!("invalid host name {}", host)));
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    // type A, class IN
+    query.extend_from_slice(&[0, 0, 1, 0, 1]);
+
+    let socket = bind(nameserver).await?;
+    socket.connect(nameserver).await?;
+    socket.send(&query).await?;
+
+    let mut buf = [0; 512];
+    loop {
+        let len = socket.recv(&mut buf).await?;
+        let response = &buf[..len];
+        if !answers(&query, response) {
+            continue;
+        }
+        return parse_response(&query, response)
+            .map(IpAddr::V4)
+            .ok_or_else(|| Error::Wpad(format!("{} does not resolve", host)));
+    }
+}
+
+/// Binds a socket to a random port to send a query to `nameserver`
+async fn bind(nameserver: SocketAddr) -> Result<UdpSocket, Error> {
+    let ip: IpAddr = if nameserver.is_ipv4() {
+        Ipv4Addr::UNSPECIFIED.into()
+    } else {
+        std::net::Ipv6Addr::UNSPECIFIED.into()
+    };
+    for _ in 0..8 {
+        let mut port = [0; 2];
+        getrandom::getrandom(&mut port).map_err(|e| Error::Wpad(e.to_string()))?;
+        let port = 1024 + u16::from_be_bytes(port) % (u16::MAX - 1024);
+        if let Ok(socket) = UdpSocket::bind(SocketAddr::new(ip, port)).await {
+            return Ok(socket);
+        }
+    }
+    // the ports tried are in use, let the system pick one
+    Ok(UdpSocket::bind(SocketAddr::new(ip, 0)).await?)
+}
+
+/// Whether `response` answers `query`: same id, and the question echoed
+fn answers(query: &[u8], response: &[u8]) -> bool {
+    let question = &query[12..];
+    response.len() >= query.len()
+        && response[..2] == query[..2]
+        // a response, to a single question
+        && response[2] & 0x80 != 0
+        && response[4..6] == [0, 1]
+        // names are compared case insensitively
+        && response[12..query.len()].eq_ignore_ascii_case(question)
+}
+
+/// Returns the first A record of a DNS response to `query`
+fn parse_response(query: &[u8], response: &[u8]) -> Option<Ipv4Addr> {
+    let rcode = response[3] & 0x0f;
+    let answers = u16::from_be_bytes([response[6], response[7]]);
+    if rcode != 0 {
+        return None;
+    }
+
+    let mut pos = query.len();
+    for _ in 0..answers {
+        pos = skip_name(response, pos)?;
+        let header = response.get(pos..pos + 10)?;
+        let kind = u16::from_be_bytes([header[0], header[1]]);
+        let len = u16::from_be_bytes([header[8], header[9]]) as usize;
+        let data = response.get(pos + 10..pos + 10 + len)?;
+        if kind == 1 && len == 4 {
+            return Some(Ipv4Addr::new(data[0], data[1], data[2], data[3]));
+        }
+        pos += 10 + len;
+    }
+    None
+}
+
+/// Returns the position after the (possibly compressed) name starting at `pos`
+fn skip_name(response: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *response.get(pos)? as usize;
+        match len {
+            0 => return Some(pos + 1),
+            l if l & 0xc0 == 0xc0 => return Some(pos + 2),
+            l => pos += l + 1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BoxConnector, PacProxy, ProxyConnector};
+    use hyper_util::client::legacy::connect::HttpConnector;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, UdpSocket};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use tokio::runtime::Runtime;
+
+    /// Answers A queries for `known` with 127.0.0.1 and NXDOMAIN for other names
+    fn dns_stub(known: &'static str) -> (SocketAddr, Arc<AtomicUsize>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let queries = Arc::new(AtomicUsize::new(0));
+        let count = queries.clone();
+        thread::spawn(move || {
+            let mut buf = [0; 512];
+            while let Ok((len, from)) = socket.recv_from(&mut buf) {
+                count.fetch_add(1, Ordering::SeqCst);
+                let query = &buf[..len];
+                let mut name = Vec::new();
+                let mut pos = 12;
+                while query[pos] != 0 {
+                    let l = query[pos] as usize;
+                    name.push(String::from_utf8_lossy(&query[pos + 1..pos + 1 + l]).into_owned());
+                    pos += l + 1;
+                }
+                let mut response = query.to_vec();
+                response[2] = 0x81;
+                if name.join(".") == known {
+                    response[3] = 0x80;
+                    response[7] = 1;
+                    response.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4]);
+                    response.extend_from_slice(&[127, 0, 0, 1]);
+                } else {
+                    response[3] = 0x83;
+                }
+                socket.send_to(&response, from).unwrap();
+            }
+        });
+        (addr, queries)
+    }
+
+    /// Serves `script` as `/wpad.dat` to the next connection
+    fn serve_pac(listener: &TcpListener, script: &str) {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut buf = [0; 1024];
+        let len = stream.read(&mut buf).unwrap();
+        let request = String::from_utf8_lossy(&buf[..len]).to_ascii_lowercase();
+        assert!(request.starts_with("get /wpad.dat http/1.1\r\n"));
+        assert!(request.contains("host: wpad.example.com\r\n"));
+        write!(
+            stream,
+            "HTTP/1.1 200 OK\r\nContent-Type: application/x-ns-proxy-autoconfig\r\n\
+             Content-Length: {}\r\n\r\n{}",
+            script.len(),
+            script
+        )
+        .unwrap();
+    }
+
+    /// Serves `script` as `/wpad.dat` to the first connection, then returns the number of bytes
+    /// received on the second one
+    fn http_server(listener: TcpListener, script: String) -> thread::JoinHandle<usize> {
+        thread::spawn(move || {
+            serve_pac(&listener, &script);
+
+            let mut buf = [0; 1024];
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.read(&mut buf).unwrap()
+        })
+    }
+
+    fn wpad(nameserver: SocketAddr, port: u16) -> Wpad {
+        let mut wpad = Wpad::with_search_domains(vec!["corp.example.com"]);
+        wpad.set_nameserver(Some(nameserver));
+        wpad.set_port(port);
+        wpad
+    }
+
+    #[test]
+    fn test_candidates() {
+        let wpad = Wpad::with_search_domains(vec!["Eng.Corp.example.com.", "example.com", "lan"]);
+        assert_eq!(
+            wpad.candidates(),
+            vec![
+                "wpad.eng.corp.example.com",
+                "wpad.corp.example.com",
+                "wpad.example.com"
+            ]
+        );
+        let wpad = Wpad::with_search_domains(vec!["eng.example.co.uk", "example.ac.jp", "co.uk"]);
+        assert_eq!(
+            wpad.candidates(),
+            vec![
+                "wpad.eng.example.co.uk",
+                "wpad.example.co.uk",
+                "wpad.example.ac.jp"
+            ]
+        );
+        assert_eq!(
+            search_domains("nameserver 10.0.0.1\ndomain lan\nsearch a.example.com b.example.com\n"),
+            vec!["a.example.com", "b.example.com"]
+        );
+    }
+
+    #[test]
+    fn test_discover() {
+        let (nameserver, queries) = dns_stub("wpad.example.com");
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let script =
+            "function FindProxyForURL(url, host) { return 'PROXY proxy.example.com:3128'; }";
+        let server = http_server(listener, script.into());
+
+        let wpad = wpad(nameserver, port);
+        let rt = Runtime::new().unwrap();
+        let connector = BoxConnector(HttpConnector::new());
+        let pac = rt.block_on(wpad.discover(connector.clone())).unwrap();
+        assert_eq!(
+            rt.block_on(pac.find_proxy(&"http://example.org/".parse().unwrap()))
+                .unwrap(),
+            vec![PacProxy::Proxy(
+                "http://proxy.example.com:3128".parse().unwrap()
+            )]
+        );
+        // wpad.corp.example.com then wpad.example.com
+        assert_eq!(queries.load(Ordering::SeqCst), 2);
+
+        // the PAC file is cached
+        rt.block_on(wpad.discover(connector)).unwrap();
+        assert_eq!(queries.load(Ordering::SeqCst), 2);
+        drop(server);
+    }
+
+    #[test]
+    fn test_discovery_expires() {
+        let (nameserver, queries) = dns_stub("wpad.example.com");
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            serve_pac(
+                &listener,
+                "function FindProxyForURL(url, host) { return 'DIRECT'; }",
+            );
+            serve_pac(
+                &listener,
+                "function FindProxyForURL(url, host) { return 'DIRECT'; }",
+            );
+        });
+
+        let mut wpad = wpad(nameserver, port);
+        wpad.set_ttl(Duration::from_millis(0));
+        let rt = Runtime::new().unwrap();
+        let connector = BoxConnector(HttpConnector::new());
+        rt.block_on(wpad.discover(connector.clone())).unwrap();
+        rt.block_on(wpad.discover(connector)).unwrap();
+        assert_eq!(queries.load(Ordering::SeqCst), 4);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_response_must_echo_question() {
+        let query = [
+            0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0, 4, b'w', b'p', b'a', b'd', 0, 0, 1, 0,
+            1,
+        ];
+        let mut response = query.to_vec();
+        response[2] = 0x81;
+        response[3] = 0x80;
+        response[7] = 1;
+        response.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 10, 0, 0, 1]);
+        assert!(answers(&query, &response));
+        assert_eq!(
+            parse_response(&query, &response),
+            Some(Ipv4Addr::new(10, 0, 0, 1))
+        );
+
+        let mut upper = response.clone();
+        upper[13] = b'W';
+        assert!(answers(&query, &upper));
+
+        let mut other_name = response.clone();
+        other_name[13] = b'x';
+        assert!(!answers(&query, &other_name));
+        let mut other_type = response.clone();
+        other_type[19] = 28;
+        assert!(!answers(&query, &other_type));
+        let mut other_class = response.clone();
+        other_class[21] = 3;
+        assert!(!answers(&query, &other_class));
+        let mut other_id = response.clone();
+        other_id[1] = 0x35;
+        assert!(!answers(&query, &other_id));
+        // the query itself, reflected
+        assert!(!answers(&query, &query));
+    }
+
+    #[test]
+    fn test_failure_backoff() {
+        let (nameserver, queries) = dns_stub("wpad.example.net");
+        let wpad = wpad(nameserver, 80);
+        let rt = Runtime::new().unwrap();
+        let connector = BoxConnector(HttpConnector::new());
+
+        assert!(rt.block_on(wpad.discover(connector.clone())).is_err());
+        assert_eq!(queries.load(Ordering::SeqCst), 2);
+        assert!(rt.block_on(wpad.discover(connector.clone())).is_err());
+        assert_eq!(queries.load(Ordering::SeqCst), 2);
+
+        // without backoff, each discovery is attempted again
+        let mut wpad = self::wpad(nameserver, 80);
+        wpad.set_backoff(Duration::from_millis(0), Duration::from_millis(0));
+        assert!(rt.block_on(wpad.discover(connector.clone())).is_err());
+        assert_eq!(queries.load(Ordering::SeqCst), 4);
+        assert!(rt.block_on(wpad.discover(connector)).is_err());
+        assert_eq!(queries.load(Ordering::SeqCst), 6);
+    }
+
+    #[test]
+    fn test_connector_uses_wpad() {
+        let (nameserver, _) = dns_stub("wpad.example.com");
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let script = format!(
+            "function FindProxyForURL(url, host) {{ return 'PROXY {}'; }}",
+            addr
+        );
+        let server = http_server(listener, script);
+
+        let mut connector = ProxyConnector::unsecured(BoxConnector(HttpConnector::new()));
+        connector.set_wpad(Some(wpad(nameserver, addr.port())));
+        let uri = "http://example.org/".parse().unwrap();
+        let stream = Runtime::new()
+            .unwrap()
+            .block_on(connector.call(uri))
+            .unwrap();
+        drop(stream);
+        // the second connection went to the proxy and was closed without data
+        assert_eq!(server.join().unwrap(), 0);
+    }
+}